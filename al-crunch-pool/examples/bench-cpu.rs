@@ -28,7 +28,7 @@ fn main() {
     };
     println!("{} for {MILLIS} ms", info.0);
 
-    let options = Options::default();
+    let options = Options::default().placement(Placement::Cpus);
     let pool = Pool::new(
         options.clone(),
         (),
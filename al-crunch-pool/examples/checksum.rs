@@ -0,0 +1,54 @@
+//! Checksum all files in directory trees with a pipeline of two pools.
+use al_crunch_pool::{Options, Pipeline, Stage};
+use std::path::PathBuf;
+
+/// Hash the data with FNV-1a.
+fn fnv(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, x| {
+        (hash ^ *x as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn main() {
+    // walk the directories on many threads as this is I/O-bound
+    let walk = Stage::new(
+        Options::default().io_bound().thread_name("walk"),
+        |_| 0usize,
+        |emit, dirs, path: PathBuf| {
+            *dirs += 1;
+            let Ok(entries) = std::fs::read_dir(&path) else {
+                return;
+            };
+            for entry in entries.flatten() {
+                match entry.file_type() {
+                    Ok(x) if x.is_dir() => emit.send(dirs, entry.path()),
+                    Ok(x) if x.is_file() => emit.emit(entry.path()),
+                    _ => {}
+                }
+            }
+        },
+    )
+    .combine(0, |a, b| a + b);
+
+    // read and hash the files with one thread per core
+    let hash = Stage::new(
+        Options::default().thread_name("hash"),
+        |_| 0u64,
+        |emit, bytes, path: PathBuf| {
+            if let Ok(data) = std::fs::read(&path) {
+                *bytes += data.len() as u64;
+                emit.emit((path, fnv(&data)));
+            }
+        },
+    )
+    .combine(0, |a, b| a + b);
+
+    let roots: Vec<PathBuf> = std::env::args().skip(1).map(PathBuf::from).collect();
+    let mut files = 0;
+    let mut checksum = 0;
+    let (dirs, bytes) = Pipeline::new(walk).then(hash).run(roots, |(_, hash)| {
+        files += 1;
+        checksum ^= hash;
+    });
+    println!("{dirs} dirs {files} files {bytes} bytes checksum {checksum:016x}");
+}
@@ -3,11 +3,27 @@
 #![feature(rustc_private)]
 extern crate libc;
 
-use al_crunch_pool::{Pool, Sender};
+use al_crunch_pool::{Options, Pool, Progress, Sender};
+
+/// The progress counters.
+const DIRS: usize = 0;
+const FILES: usize = 1;
+
+/// The subdirectories sent in a single job.
+const BATCH: usize = 16;
+
+/// Send the subdirectories in a batch.
+fn send(sender: &Sender<WorkerState>, children: &mut Vec<FileDescriptor>, state: &mut WorkerState) {
+    sender.send_batch(state, BATCH, children.drain(..), |sender, state, child| {
+        visit(sender, child, state)
+    });
+}
 
 /// Visit the directories recursively.
 fn visit(sender: &Sender<WorkerState>, file: FileDescriptor, state: &mut WorkerState) {
+    sender.progress(DIRS, 1);
     let mut buf = [0u8; 4096];
+    let mut children = Vec::with_capacity(BATCH);
     loop {
         let s = unsafe { libc::syscall(libc::SYS_getdents64, file.0, buf.as_mut_ptr(), buf.len()) };
         if s < 1 {
@@ -32,11 +48,10 @@ fn visit(sender: &Sender<WorkerState>, file: FileDescriptor, state: &mut WorkerS
                 {
                     continue;
                 }
-                let child = FileDescriptor::new(&file, entry.d_name.as_ptr()).unwrap();
-                let sender2 = sender.clone();
-                sender.send(state, move |state| {
-                    visit(&sender2, child, state);
-                });
+                children.push(FileDescriptor::new(&file, entry.d_name.as_ptr()).unwrap());
+                if children.len() == BATCH {
+                    send(sender, &mut children, state);
+                }
             } else {
                 let mut stat = core::mem::MaybeUninit::<libc::stat>::uninit();
                 let res = unsafe {
@@ -53,12 +68,14 @@ fn visit(sender: &Sender<WorkerState>, file: FileDescriptor, state: &mut WorkerS
                 let stat = unsafe { stat.assume_init() };
                 state.blocks += stat.st_blocks as u64;
                 state.count += 1;
+                sender.progress(FILES, 1);
             }
         }
         if !more {
             break;
         }
     }
+    send(sender, &mut children, state);
 }
 
 /// A type to wrap a file-descriptor to close on drop.
@@ -89,22 +106,25 @@ struct WorkerState {
 
 fn main() {
     let curwd = FileDescriptor(libc::AT_FDCWD);
+    let options = Options::default().progress(Progress::new(&["dirs", "files"]));
+    let pool = Pool::new(options, (), |_| WorkerState::default(), |x| x);
     for path in std::env::args().skip(1) {
         let cpath = std::ffi::CString::new(path.clone()).unwrap();
-        let pool = Pool::default();
 
-        let state = &mut Default::default();
+        let state = &mut WorkerState::default();
         let sender = pool.sender().clone();
         let fd = FileDescriptor::new(&curwd, cpath.as_ptr()).unwrap();
         pool.sender().send(state, move |state: &mut WorkerState| {
             visit(&sender, fd, state);
         });
 
-        // aggregate the count of all workers
-        for v in pool.join() {
+        // wait for the tree and aggregate the count of all workers
+        pool.wait_idle();
+        for v in pool.broadcast(std::mem::take) {
             state.count += v.count;
             state.blocks += v.blocks;
         }
         println!("{} {} {}", path, state.count, state.blocks << 9);
     }
+    pool.join();
 }
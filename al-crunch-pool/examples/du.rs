@@ -1,8 +1,12 @@
 //! Count bytes in a directory tree - optimized version.
-use al_crunch_pool::{execute, Options, Sender};
+use al_crunch_pool::{execute, Options, Progress, Sender};
 use std::os::linux::fs::MetadataExt;
 use std::path::Path;
 
+/// The progress counters.
+const ENTRIES: usize = 0;
+const BYTES: usize = 1;
+
 /// Recursively visit the directories.
 fn visit(
     sender: &Sender<WorkerState>,
@@ -13,9 +17,14 @@ fn visit(
     let metadata = path.metadata().unwrap();
     state.size += metadata.st_blocks();
     state.count += 1;
+    sender.progress(ENTRIES, 1);
+    sender.progress(BYTES, metadata.st_blocks() << 9);
 
     for entry in std::fs::read_dir(path)? {
         let entry = entry?;
+        if sender.is_cancelled() {
+            break;
+        }
 
         // recurse if needed
         if entry.file_type()?.is_dir() {
@@ -24,13 +33,16 @@ fn visit(
                 let _ = visit(sender, &path, state);
             } else {
                 let sender2 = sender.clone();
-                sender.send(state, move |state| {
+                sender.send_labeled(state, "visit", move |state| {
                     let _ = visit(&sender2, &path, state);
                 });
             }
         } else {
-            state.size += entry.metadata()?.st_blocks();
+            let blocks = entry.metadata()?.st_blocks();
+            state.size += blocks;
             state.count += 1;
+            sender.progress(ENTRIES, 1);
+            sender.progress(BYTES, blocks << 9);
         }
     }
     Ok(())
@@ -45,17 +57,25 @@ pub struct WorkerState {
 
 fn main() -> std::io::Result<()> {
     for path in std::env::args().skip(1) {
-        let options = Options::default().one_is_zero().io_bound();
+        let options = Options::default()
+            .one_is_zero()
+            .io_bound()
+            .thread_name("du")
+            .cancel_on_signals()
+            .progress(Progress::new(&["entries", "bytes"]));
 
-        let pn = path.clone();
+        let root = Path::new(&path);
+        let mut cancel = None;
         let state = execute(
             options.clone(),
             |_| Default::default(),
-            move |sender| {
+            |x| x,
+            |sender| {
+                cancel = Some(sender.cancel_token());
                 let mut state = Default::default();
                 let sender2 = sender.clone();
                 sender.send(&mut state, move |state| {
-                    visit(&sender2, Path::new(&pn), state).unwrap();
+                    visit(&sender2, root, state).unwrap();
                 });
                 state
             },
@@ -67,6 +87,9 @@ fn main() -> std::io::Result<()> {
         );
 
         println!("{path} {} {}", state.count, state.size << 9);
+        if cancel.is_some_and(|x| x.is_cancelled()) {
+            std::process::exit(130);
+        }
     }
     Ok(())
 }
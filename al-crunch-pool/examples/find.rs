@@ -46,7 +46,12 @@ impl Default for WorkerState {
 }
 
 fn main() -> std::io::Result<()> {
-    let pool = Options::default().one_is_zero().io_bound().build();
+    let pool = Options::default()
+        .one_is_zero()
+        .io_bound()
+        .cancel_on_signals()
+        .build();
+    let cancel = pool.cancel_token();
     for path in std::env::args().skip(1) {
         // output the path to be compatible with find(1)
         println!("{path}");
@@ -56,6 +61,10 @@ fn main() -> std::io::Result<()> {
         });
     }
 
+    // the writers are flushed when the workers finish
     pool.join();
+    if cancel.is_cancelled() {
+        std::process::exit(130);
+    }
     Ok(())
 }
@@ -0,0 +1,74 @@
+//! Sort random numbers with a parallel merge sort.
+use al_crunch_pool::{execute, Options, Sender};
+
+/// Below this size the vectors are sorted sequentially.
+const SEQUENTIAL: usize = 1 << 14;
+
+/// Sort the vector by sorting both halves in parallel.
+fn sort(sender: &Sender<usize>, state: &mut usize, mut data: Vec<u64>) -> Vec<u64> {
+    if data.len() <= SEQUENTIAL {
+        data.sort_unstable();
+        return data;
+    }
+    *state += 1;
+
+    // sort the right half on another worker
+    let right = data.split_off(data.len() / 2);
+    let sender2 = sender.clone();
+    let (left, right) = sender.join(
+        state,
+        |state| sort(sender, state, data),
+        move |state| sort(&sender2, state, right),
+    );
+    merge(left, right)
+}
+
+/// Merge two sorted vectors.
+fn merge(left: Vec<u64>, right: Vec<u64>) -> Vec<u64> {
+    let mut res = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        res.push(if a <= b { left.next() } else { right.next() }.unwrap());
+    }
+    res.extend(left);
+    res.extend(right);
+    res
+}
+
+fn main() {
+    let count = std::env::args()
+        .nth(1)
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(10_000_000);
+
+    // a simple xorshift generator
+    let mut x = 0x2545f4914f6cdd1du64;
+    let data: Vec<u64> = (0..count)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        })
+        .collect();
+
+    let start = std::time::Instant::now();
+    let (sorted, splits) = execute(
+        Options::default(),
+        |_| 0,
+        |x| x,
+        |sender| {
+            let sender2 = sender.clone();
+            let handle = sender.spawn(&mut 0, move |state| sort(&sender2, state, data));
+            (handle.wait().unwrap(), 0)
+        },
+        |(sorted, splits), y| (sorted, splits + y),
+    );
+    assert!(sorted.windows(2).all(|x| x[0] <= x[1]));
+    println!(
+        "sorted {} numbers with {splits} splits in {:?}",
+        sorted.len(),
+        start.elapsed()
+    );
+}
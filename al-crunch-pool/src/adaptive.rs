@@ -0,0 +1,48 @@
+//! Adapting the number of workers to the load.
+
+use crate::{worker::Workers, Parallelism};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// How often the workers are inspected.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Return the scheduler state of a thread in the current process.
+fn state(tid: i32) -> Option<u8> {
+    let stat = std::fs::read_to_string(format!("/proc/self/task/{tid}/stat")).ok()?;
+    stat.rsplit_once(')')?.1.trim_start().bytes().next()
+}
+
+/// Grow and shrink the workers until all of them are gone.
+///
+/// A worker is blocked if it sleeps in the kernel while running a job.  The
+/// pool grows while more than half of the workers are blocked and jobs are
+/// waiting.  It shrinks while there are at least as many running workers as
+/// CPUs, but not below `min`.
+pub(crate) fn monitor<W, H, J>(workers: &Workers<'_, '_, W, H, J>, min: usize) {
+    let cpus = Parallelism::cached().get();
+    let shared = &workers.shared;
+    while shared.workers() > 0 {
+        std::thread::park_timeout(TICK);
+
+        let (mut blocked, mut running) = (0, 0);
+        for activity in &shared.activity {
+            let tid = activity.tid.load(Ordering::Relaxed);
+            if tid == 0 {
+                continue;
+            }
+            match state(tid) {
+                Some(b'R') => running += 1,
+                Some(b'D' | b'S') if activity.busy.load(Ordering::Relaxed) => blocked += 1,
+                _ => {}
+            }
+        }
+
+        let active = shared.workers();
+        if 2 * blocked > active && !shared.is_empty() {
+            workers.add();
+        } else if running >= cpus && active > min {
+            shared.retire();
+        }
+    }
+}
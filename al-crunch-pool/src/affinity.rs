@@ -0,0 +1,261 @@
+//! CPU affinity of the workers.
+
+use std::io::{Error, ErrorKind, Result};
+
+/// A set of CPUs.
+#[derive(Clone, Copy)]
+pub struct CpuSet(libc::cpu_set_t);
+
+impl CpuSet {
+    /// Create an empty set.
+    pub fn new() -> Self {
+        Self(unsafe { core::mem::zeroed() })
+    }
+
+    /// Add a CPU to the set.
+    ///
+    /// Panics if the CPU does not fit into a `cpu_set_t`.
+    pub fn insert(&mut self, cpu: usize) {
+        assert!(cpu < libc::CPU_SETSIZE as usize, "CPU {cpu} out of range");
+        unsafe { libc::CPU_SET(cpu, &mut self.0) };
+    }
+
+    /// Return true if the CPU is in the set.
+    pub fn contains(&self, cpu: usize) -> bool {
+        cpu < libc::CPU_SETSIZE as usize && unsafe { libc::CPU_ISSET(cpu, &self.0) }
+    }
+
+    /// Return the number of CPUs in the set.
+    pub fn len(&self) -> usize {
+        unsafe { libc::CPU_COUNT(&self.0) as usize }
+    }
+
+    /// Return true if the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterate over the CPUs in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..libc::CPU_SETSIZE as usize).filter(|x| self.contains(*x))
+    }
+
+    /// Return the CPUs that are in both sets.
+    pub fn intersection(&self, other: &Self) -> Self {
+        self.iter().filter(|x| other.contains(*x)).collect()
+    }
+
+    /// Parse a list like `0-3,8` as found in sysfs.
+    pub fn parse(list: &str) -> Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("invalid CPU list {list:?}"));
+        let mut res = Self::new();
+        for range in list.trim().split(',').filter(|x| !x.is_empty()) {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let start: usize = start.parse().map_err(|_| invalid())?;
+            let end: usize = end.parse().map_err(|_| invalid())?;
+            if end < start || end >= libc::CPU_SETSIZE as usize {
+                return Err(invalid());
+            }
+            (start..=end).for_each(|x| res.insert(x));
+        }
+        Ok(res)
+    }
+
+    /// Return the affinity mask of the current thread.
+    pub fn current() -> Result<Self> {
+        let mut res = Self::new();
+        let x = unsafe {
+            libc::sched_getaffinity(0, core::mem::size_of::<libc::cpu_set_t>(), &mut res.0)
+        };
+        if x != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(res)
+    }
+
+    /// Pin the current thread to the set.
+    pub fn apply(&self) -> Result<()> {
+        let x =
+            unsafe { libc::sched_setaffinity(0, core::mem::size_of::<libc::cpu_set_t>(), &self.0) };
+        if x != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Return the CPUs of the NUMA nodes in the order of the nodes.
+    ///
+    /// A system without NUMA support is a single node with all CPUs.
+    pub fn numa_nodes() -> Result<Vec<Self>> {
+        let dir = match std::fs::read_dir("/sys/devices/system/node") {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![Self::current()?]),
+            Err(e) => return Err(e),
+        };
+        let mut nodes = Vec::new();
+        for entry in dir {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(Ok(node)) = name
+                .to_str()
+                .and_then(|x| x.strip_prefix("node"))
+                .map(|x| x.parse::<usize>())
+            else {
+                continue;
+            };
+            let list = std::fs::read_to_string(entry.path().join("cpulist"))?;
+            nodes.push((node, Self::parse(&list)?));
+        }
+        nodes.sort_by_key(|x| x.0);
+        Ok(nodes.into_iter().map(|x| x.1).collect())
+    }
+}
+
+impl Default for CpuSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PartialEq for CpuSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for CpuSet {}
+
+impl FromIterator<usize> for CpuSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut res = Self::new();
+        iter.into_iter().for_each(|x| res.insert(x));
+        res
+    }
+}
+
+impl std::fmt::Debug for CpuSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// Where the workers are placed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Placement {
+    /// Leave the placement to the scheduler.
+    #[default]
+    Any,
+    /// Pin each worker to a single CPU of the current affinity mask.
+    Cpus,
+    /// Pin the workers round-robin to the given sets.
+    Sets(Vec<CpuSet>),
+    /// Group the workers per NUMA node and pin them to the CPUs of their node.
+    ///
+    /// The nodes are restricted to the current affinity mask.  They get workers
+    /// in proportion to their CPUs.
+    Numa,
+}
+
+impl Placement {
+    /// Return the CPUs of the workers.
+    pub(crate) fn resolve(&self, threads: usize) -> Result<Vec<Option<CpuSet>>> {
+        let sets: Vec<CpuSet> = match self {
+            Self::Any => return Ok(vec![None; threads]),
+            Self::Cpus => CpuSet::current()?
+                .iter()
+                .map(|x| [x].into_iter().collect())
+                .collect(),
+            Self::Sets(sets) => sets.clone(),
+            Self::Numa => {
+                let current = CpuSet::current()?;
+                CpuSet::numa_nodes()?
+                    .into_iter()
+                    .map(|x| x.intersection(&current))
+                    .filter(|x| !x.is_empty())
+                    .collect()
+            }
+        };
+        if sets.is_empty() || sets.iter().any(|x| x.is_empty()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "no CPUs to place the workers on",
+            ));
+        }
+        Ok(match self {
+            Self::Numa => spread(&sets, threads),
+            _ => (0..threads).map(|i| Some(sets[i % sets.len()])).collect(),
+        })
+    }
+}
+
+/// Assign the workers to the nodes in proportion to their CPUs.
+///
+/// With the CPUs of all nodes laid out in a row, worker `i` goes to the node
+/// of CPU `i * cpus / threads`.
+fn spread(nodes: &[CpuSet], threads: usize) -> Vec<Option<CpuSet>> {
+    let cpus: Vec<_> = nodes
+        .iter()
+        .flat_map(|x| std::iter::repeat_n(x, x.len()))
+        .collect();
+    (0..threads)
+        .map(|i| Some(*cpus[i * cpus.len() / threads]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spread, CpuSet, Placement};
+
+    #[test]
+    fn parse() {
+        let set = CpuSet::parse("0-3,8\n").unwrap();
+        assert_eq!(set.iter().collect::<Vec<_>>(), [0, 1, 2, 3, 8]);
+        assert_eq!(set.len(), 5);
+        assert!(CpuSet::parse("").unwrap().is_empty());
+        assert_eq!(CpuSet::parse("5").unwrap(), [5].into_iter().collect());
+        for list in ["3-1", "a", "1-", "0-100000"] {
+            assert!(CpuSet::parse(list).is_err(), "{list}");
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        assert_eq!(Placement::Any.resolve(2).unwrap(), [None, None]);
+
+        let sets = vec![CpuSet::parse("0-1").unwrap(), CpuSet::parse("2").unwrap()];
+        let placed = Placement::Sets(sets.clone()).resolve(3).unwrap();
+        assert_eq!(placed, [Some(sets[0]), Some(sets[1]), Some(sets[0])]);
+        assert!(Placement::Sets(vec![]).resolve(1).is_err());
+        assert!(Placement::Sets(vec![CpuSet::new()]).resolve(1).is_err());
+
+        let current = CpuSet::current().unwrap();
+        for cpus in Placement::Cpus.resolve(current.len()).unwrap() {
+            let cpus = cpus.unwrap();
+            assert_eq!(cpus.len(), 1);
+            assert_eq!(cpus.intersection(&current), cpus);
+        }
+        for cpus in Placement::Numa.resolve(3).unwrap() {
+            assert!(!cpus.unwrap().intersection(&current).is_empty());
+        }
+    }
+
+    #[test]
+    fn numa() {
+        let nodes = [CpuSet::parse("0-3").unwrap(), CpuSet::parse("4-7").unwrap()];
+        let node = |threads| -> Vec<_> {
+            spread(&nodes, threads)
+                .into_iter()
+                .map(|x| nodes.iter().position(|y| Some(*y) == x).unwrap())
+                .collect()
+        };
+        assert_eq!(node(1), [0]);
+        assert_eq!(node(2), [0, 1]);
+        assert_eq!(node(3), [0, 0, 1]);
+        assert_eq!(node(16), [[0; 8], [1; 8]].concat());
+
+        let nodes = [CpuSet::parse("0-5").unwrap(), CpuSet::parse("6-7").unwrap()];
+        let placed = spread(&nodes, 4);
+        assert_eq!(placed[..3], [Some(nodes[0]); 3]);
+        assert_eq!(placed[3], Some(nodes[1]));
+    }
+}
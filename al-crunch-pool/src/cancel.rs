@@ -0,0 +1,71 @@
+//! Cooperative cancellation.
+
+use crate::SignalGuard;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A handle to cancel the jobs of a pool.
+///
+/// Queued jobs are dropped without running and new jobs are ignored once it
+/// is cancelled.  Running jobs may poll `is_cancelled` to stop early.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(pub(crate) Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Cancel all jobs.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// Return true if the jobs were cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Cancel the jobs when the process receives one of the signals.
+    ///
+    /// The token stays registered while the returned guard lives or until
+    /// one of the signals fired.  The previous handler of a signal is restored
+    /// once no token is registered for it anymore, so that a second signal
+    /// terminates the process by default.  Fails if the handlers cannot be installed.
+    pub fn cancel_on_signals(&self, signals: &[libc::c_int]) -> std::io::Result<SignalGuard> {
+        crate::signal::register(self, signals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{execute, Options};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn drop_jobs() {
+        let ran = Arc::new(AtomicUsize::new(0));
+        let options = Options::default().threads(Some(1));
+        execute(
+            options,
+            |_| (),
+            |x| x,
+            |s| {
+                let sender = s.clone();
+                let ran = ran.clone();
+                s.send(&mut (), move |state| {
+                    for _ in 0..3 {
+                        let ran = ran.clone();
+                        sender.send(state, move |_| {
+                            ran.fetch_add(1, Ordering::SeqCst);
+                        });
+                    }
+                    sender.cancel_token().cancel();
+                    assert!(sender.is_cancelled());
+                    sender.send(state, move |_| {
+                        ran.fetch_add(1, Ordering::SeqCst);
+                    });
+                });
+            },
+            |_, _| (),
+        );
+        assert_eq!(ran.load(Ordering::SeqCst), 0);
+    }
+}
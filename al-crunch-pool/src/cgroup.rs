@@ -0,0 +1,214 @@
+//! Detecting the CPU quota of the cgroup hierarchy.
+
+use crate::CpuSet;
+use std::io::{ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// A CPU quota of a cgroup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuQuota {
+    /// The cgroup directory the quota was read from.
+    pub path: PathBuf,
+    /// The runtime allowed per period in microseconds.
+    pub quota: u64,
+    /// The length of a period in microseconds.
+    pub period: u64,
+}
+
+impl CpuQuota {
+    /// Return the number of CPUs the quota allows, rounded up.
+    pub fn cpus(&self) -> usize {
+        self.quota.div_ceil(self.period).max(1) as usize
+    }
+
+    /// Return true if the quota allows less CPU time than the other one.
+    fn is_below(&self, other: &Self) -> bool {
+        (self.quota as u128 * other.period as u128) < (other.quota as u128 * self.period as u128)
+    }
+
+    /// Create a quota unless the period is zero.
+    fn new(dir: &Path, quota: u64, period: u64) -> Option<Self> {
+        (period > 0).then(|| Self {
+            path: dir.to_path_buf(),
+            quota,
+            period,
+        })
+    }
+
+    /// Parse the contents of `cpu.max` in cgroup v2.
+    fn parse_max(dir: &Path, max: &str) -> Option<Self> {
+        let (quota, period) = max.trim().split_once(' ')?;
+        Self::new(dir, quota.parse().ok()?, period.parse().ok()?)
+    }
+
+    /// Parse the contents of `cpu.cfs_quota_us` and `cpu.cfs_period_us` in cgroup v1.
+    fn parse_cfs(dir: &Path, quota: &str, period: &str) -> Option<Self> {
+        // a negative quota means unlimited
+        Self::new(dir, quota.trim().parse().ok()?, period.trim().parse().ok()?)
+    }
+
+    /// Read the quota of a single cgroup directory.
+    ///
+    /// This is `cpu.max` in cgroup v2 and `cpu.cfs_quota_us` in cgroup v1.
+    fn read(dir: &Path, v2: bool) -> Result<Option<Self>> {
+        let read = |name: &str| match std::fs::read_to_string(dir.join(name)) {
+            Ok(x) => Ok(Some(x)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+        Ok(if v2 {
+            read("cpu.max")?.and_then(|x| Self::parse_max(dir, &x))
+        } else {
+            match (read("cpu.cfs_quota_us")?, read("cpu.cfs_period_us")?) {
+                (Some(quota), Some(period)) => Self::parse_cfs(dir, &quota, &period),
+                _ => None,
+            }
+        })
+    }
+
+    /// Return the most restrictive quota in the cgroup hierarchy of the current process.
+    ///
+    /// The cgroups are located through `/proc/self/cgroup` and `/proc/self/mountinfo`.
+    /// All parents up to the mount point are considered as well.
+    pub fn current() -> Result<Option<Self>> {
+        let cgroups = std::fs::read_to_string("/proc/self/cgroup")?;
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")?;
+        let mut res: Option<Self> = None;
+        for line in mountinfo.lines() {
+            let Some((mount, fs)) = line.split_once(" - ") else {
+                continue;
+            };
+            let mount: Vec<_> = mount.split(' ').collect();
+            let fs: Vec<_> = fs.split(' ').collect();
+            if mount.len() < 5 || fs.len() < 3 {
+                continue;
+            }
+            let v2 = match fs[0] {
+                "cgroup2" => true,
+                "cgroup" if fs[2].split(',').any(|x| x == "cpu") => false,
+                _ => continue,
+            };
+
+            // the path of our cgroup inside this hierarchy
+            let Some(path) = cgroups.lines().find_map(|x| {
+                let mut fields = x.splitn(3, ':');
+                let (_, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+                let found = match v2 {
+                    true => controllers.is_empty(),
+                    false => controllers.split(',').any(|x| x == "cpu"),
+                };
+                found.then_some(path)
+            }) else {
+                continue;
+            };
+            let Ok(relative) = Path::new(path).strip_prefix(mount[3]) else {
+                continue;
+            };
+
+            // walk up to the mount point
+            let top = Path::new(mount[4]);
+            let mut dir = top.join(relative);
+            loop {
+                if let Some(quota) = Self::read(&dir, v2)? {
+                    if res.as_ref().is_none_or(|x| quota.is_below(x)) {
+                        res = Some(quota);
+                    }
+                }
+                if dir == top || !dir.pop() {
+                    break;
+                }
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// The parallelism detected for the current process.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Parallelism {
+    /// The number of CPUs in the affinity mask.
+    pub cpus: usize,
+    /// The most restrictive CPU quota of the cgroup hierarchy.
+    pub quota: Option<CpuQuota>,
+}
+
+impl Parallelism {
+    /// Detect the CPUs and the quota of the current process.
+    ///
+    /// Falls back to `available_parallelism` if the affinity mask cannot be
+    /// read and ignores errors reading the cgroups.
+    pub fn detect() -> Self {
+        let cpus = CpuSet::current().map(|x| x.len()).unwrap_or_else(|_| {
+            std::thread::available_parallelism()
+                .map(|x| x.get())
+                .unwrap_or(0)
+        });
+        Self {
+            cpus,
+            quota: CpuQuota::current().ok().flatten(),
+        }
+    }
+
+    /// Return the parallelism detected on the first call.
+    ///
+    /// The pools use this, so that the cgroups are read only once per process.
+    pub fn cached() -> &'static Self {
+        static CACHED: OnceLock<Parallelism> = OnceLock::new();
+        CACHED.get_or_init(Self::detect)
+    }
+
+    /// Return the number of threads that can run in parallel.
+    pub fn get(&self) -> usize {
+        match &self.quota {
+            Some(quota) => self.cpus.min(quota.cpus()),
+            None => self.cpus,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuQuota;
+    use std::path::Path;
+
+    fn quota(quota: u64, period: u64) -> Option<CpuQuota> {
+        Some(CpuQuota {
+            path: "/sys/fs/cgroup/x".into(),
+            quota,
+            period,
+        })
+    }
+
+    #[test]
+    fn parse_max() {
+        let dir = Path::new("/sys/fs/cgroup/x");
+        assert_eq!(
+            CpuQuota::parse_max(dir, "150000 100000\n"),
+            quota(150000, 100000)
+        );
+        assert_eq!(CpuQuota::parse_max(dir, "max 100000\n"), None);
+        assert_eq!(CpuQuota::parse_max(dir, "50000 0\n"), None);
+        assert_eq!(CpuQuota::parse_max(dir, ""), None);
+    }
+
+    #[test]
+    fn parse_cfs() {
+        let dir = Path::new("/sys/fs/cgroup/x");
+        assert_eq!(
+            CpuQuota::parse_cfs(dir, "200000\n", "100000\n"),
+            quota(200000, 100000)
+        );
+        assert_eq!(CpuQuota::parse_cfs(dir, "-1\n", "100000\n"), None);
+        assert_eq!(CpuQuota::parse_cfs(dir, "200000\n", "0\n"), None);
+    }
+
+    #[test]
+    fn cpus() {
+        assert_eq!(quota(150000, 100000).unwrap().cpus(), 2);
+        assert_eq!(quota(200000, 100000).unwrap().cpus(), 2);
+        assert_eq!(quota(1000, 100000).unwrap().cpus(), 1);
+        let (a, b) = (quota(50000, 100000).unwrap(), quota(100000, 50000).unwrap());
+        assert!(a.is_below(&b) && !b.is_below(&a));
+    }
+}
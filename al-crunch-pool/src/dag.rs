@@ -0,0 +1,334 @@
+//! Tasks with dependencies.
+
+use crate::{error, Sender};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Identifies a task of a `Dag`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(usize);
+
+impl TaskId {
+    /// Return the index of the task in the order the tasks were added.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// The task function.
+type TaskFunction<'env, W, E> = Box<dyn FnOnce(&mut W) -> Result<(), E> + Send + 'env>;
+
+/// A task with the tasks it depends on.
+struct Task<'env, W, E> {
+    job: TaskFunction<'env, W, E>,
+    deps: Vec<usize>,
+}
+
+/// A graph of tasks that run once all their dependencies have succeeded.
+///
+/// See `execute_dag`.
+pub struct Dag<'env, W, E> {
+    tasks: Vec<Task<'env, W, E>>,
+}
+
+impl<'env, W, E> Dag<'env, W, E> {
+    /// Create an empty graph.
+    pub fn new() -> Self {
+        Self { tasks: Vec::new() }
+    }
+
+    /// Add a task.
+    pub fn add<F>(&mut self, job: F) -> TaskId
+    where
+        F: FnOnce(&mut W) -> Result<(), E> + Send + 'env,
+    {
+        self.tasks.push(Task {
+            job: Box::new(job),
+            deps: Vec::new(),
+        });
+        TaskId(self.tasks.len() - 1)
+    }
+
+    /// Let the task start only after the other one has succeeded.
+    ///
+    /// Panics if one of the tasks is not part of the graph.
+    pub fn depend(&mut self, task: TaskId, on: TaskId) {
+        assert!(on.0 < self.tasks.len(), "unknown task {on:?}");
+        self.tasks[task.0].deps.push(on.0);
+    }
+
+    /// Return the number of tasks.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Return true if there are no tasks.
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Find a cycle in the dependencies.
+    fn check(&self) -> Result<(), CycleError> {
+        // remove the tasks without pending dependencies until none are left
+        let mut remaining: Vec<_> = self.tasks.iter().map(|x| x.deps.len()).collect();
+        let mut dependents = vec![Vec::new(); self.tasks.len()];
+        for (i, task) in self.tasks.iter().enumerate() {
+            task.deps.iter().for_each(|&x| dependents[x].push(i));
+        }
+        let mut ready: Vec<_> = (0..remaining.len())
+            .filter(|&x| remaining[x] == 0)
+            .collect();
+        while let Some(i) = ready.pop() {
+            for &x in &dependents[i] {
+                remaining[x] -= 1;
+                if remaining[x] == 0 {
+                    ready.push(x);
+                }
+            }
+        }
+        let Some(start) = remaining.iter().position(|&x| x > 0) else {
+            return Ok(());
+        };
+
+        // follow the unresolved dependencies until a task repeats
+        let mut path = vec![start];
+        loop {
+            let last = *path.last().unwrap();
+            let next = *self.tasks[last]
+                .deps
+                .iter()
+                .find(|&&x| remaining[x] > 0)
+                .unwrap();
+            if let Some(pos) = path.iter().position(|&x| x == next) {
+                let cycle = path[pos..].iter().map(|&x| TaskId(x)).collect();
+                return Err(CycleError { cycle });
+            }
+            path.push(next);
+        }
+    }
+
+    /// Check the dependencies and turn the graph into a schedule.
+    ///
+    /// The panics of the tasks are caught if requested.
+    pub(crate) fn schedule(self, catch_panics: bool) -> Result<Schedule<'env, W, E>, CycleError> {
+        self.check()?;
+        let mut nodes: Vec<_> = self
+            .tasks
+            .iter()
+            .map(|x| Node {
+                job: Mutex::new(None),
+                dependents: Vec::new(),
+                remaining: AtomicUsize::new(x.deps.len()),
+                failed: AtomicBool::new(false),
+                outcome: Mutex::new(Outcome::Skipped),
+            })
+            .collect();
+        for (i, task) in self.tasks.into_iter().enumerate() {
+            task.deps.iter().for_each(|&x| nodes[x].dependents.push(i));
+            nodes[i].job = Mutex::new(Some(task.job));
+        }
+        Ok(Schedule {
+            nodes,
+            catch_panics,
+        })
+    }
+}
+
+impl<W, E> Default for Dag<'_, W, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What happened to a task.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome<E> {
+    /// The task has succeeded.
+    Done,
+    /// The task has returned an error.
+    Failed(E),
+    /// The task panicked with the given message.
+    ///
+    /// This is only reported with `catch_panics`.
+    Panicked(String),
+    /// The task did not run as one of its dependencies failed, panicked or was skipped.
+    Skipped,
+}
+
+/// The error returned if the dependencies have a cycle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CycleError {
+    /// The tasks on the cycle.  Each one depends on the next and the last one on the first.
+    pub cycle: Vec<TaskId>,
+}
+
+impl std::fmt::Display for CycleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "dependency cycle between tasks ")?;
+        for task in &self.cycle {
+            write!(f, "{} -> ", task.0)?;
+        }
+        write!(f, "{}", self.cycle[0].0)
+    }
+}
+
+impl std::error::Error for CycleError {}
+
+/// The scheduling state of a task.
+struct Node<'env, W, E> {
+    /// The function until the task runs.
+    job: Mutex<Option<TaskFunction<'env, W, E>>>,
+    /// The tasks depending on this one.
+    dependents: Vec<usize>,
+    /// The number of dependencies that have not finished yet.
+    remaining: AtomicUsize,
+    /// Did one of the dependencies fail?
+    failed: AtomicBool,
+    outcome: Mutex<Outcome<E>>,
+}
+
+/// The tasks of a graph while they are executed.
+pub(crate) struct Schedule<'env, W, E> {
+    nodes: Vec<Node<'env, W, E>>,
+    catch_panics: bool,
+}
+
+impl<'env, W: 'env, E: Send + 'env> Schedule<'env, W, E> {
+    /// Send the tasks without dependencies.
+    pub(crate) fn start(self: &Arc<Self>, sender: &Sender<'env, W>, state: &mut W) {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.remaining.load(Ordering::SeqCst) == 0 {
+                self.send(sender, state, i);
+            }
+        }
+    }
+
+    /// Send a task that is ready.
+    fn send(self: &Arc<Self>, sender: &Sender<'env, W>, state: &mut W, index: usize) {
+        let schedule = self.clone();
+        let sender2 = sender.clone();
+        sender.send(state, move |state| schedule.run(&sender2, state, index));
+    }
+
+    /// Run the task and release its dependents.
+    fn run(self: &Arc<Self>, sender: &Sender<'env, W>, state: &mut W, index: usize) {
+        let Some(job) = self.nodes[index].job.lock().unwrap().take() else {
+            return;
+        };
+        let res = match self.catch_panics {
+            true => catch_unwind(AssertUnwindSafe(|| job(state))),
+            false => Ok(job(state)),
+        };
+        let outcome = match res {
+            Ok(Ok(())) => Outcome::Done,
+            Ok(Err(e)) => Outcome::Failed(e),
+            Err(payload) => {
+                let message = error::message(&*payload).unwrap_or("Box<dyn Any>");
+                Outcome::Panicked(message.to_string())
+            }
+        };
+        let failed = !matches!(outcome, Outcome::Done);
+        *self.nodes[index].outcome.lock().unwrap() = outcome;
+
+        // skip the dependents of failed tasks transitively
+        let mut finished = vec![(index, failed)];
+        while let Some((index, failed)) = finished.pop() {
+            for &i in &self.nodes[index].dependents {
+                let node = &self.nodes[i];
+                if failed {
+                    node.failed.store(true, Ordering::SeqCst);
+                }
+                if node.remaining.fetch_sub(1, Ordering::SeqCst) == 1 {
+                    match node.failed.load(Ordering::SeqCst) {
+                        true => finished.push((i, true)),
+                        false => self.send(sender, state, i),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Return the outcomes in the order of the tasks.
+    pub(crate) fn outcomes(self) -> Vec<Outcome<E>> {
+        self.nodes
+            .into_iter()
+            .map(|x| x.outcome.into_inner().unwrap())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CycleError, Dag, Outcome, TaskId};
+    use crate::{execute_dag, Options};
+
+    /// Return a task recording its number.
+    fn push(i: usize) -> impl FnOnce(&mut Vec<usize>) -> Result<(), &'static str> {
+        move |x| {
+            x.push(i);
+            Ok(())
+        }
+    }
+
+    /// Execute the graph and return the outcomes and the tasks that ran.
+    fn run(dag: Dag<'_, Vec<usize>, &'static str>) -> (Vec<usize>, Vec<Outcome<&'static str>>) {
+        let options = Options::default().threads(Some(2)).catch_panics();
+        let (mut ran, outcomes) = execute_dag(
+            options,
+            |_| Vec::new(),
+            |x| x,
+            dag,
+            Vec::new(),
+            |mut a, b| {
+                a.extend(b);
+                a
+            },
+        )
+        .unwrap();
+        ran.sort();
+        (ran, outcomes)
+    }
+
+    #[test]
+    fn cycle() {
+        let mut dag = Dag::<(), ()>::new();
+        let t: Vec<_> = (0..4).map(|_| dag.add(|_| Ok(()))).collect();
+        dag.depend(t[1], t[0]);
+        dag.depend(t[1], t[2]);
+        dag.depend(t[2], t[3]);
+        dag.depend(t[3], t[1]);
+        let cycle = vec![TaskId(1), TaskId(2), TaskId(3)];
+        assert_eq!(dag.check(), Err(CycleError { cycle }));
+    }
+
+    #[test]
+    fn skip_dependents() {
+        let mut dag = Dag::new();
+        let a = dag.add(push(0));
+        let b = dag.add(|_| Err("failed"));
+        let c = dag.add(push(2));
+        let d = dag.add(push(3));
+        let e = dag.add(push(4));
+        dag.depend(c, a);
+        dag.depend(d, b);
+        dag.depend(e, d);
+        let (ran, outcomes) = run(dag);
+        assert_eq!(ran, [0, 2]);
+        use Outcome::*;
+        assert_eq!(outcomes, [Done, Failed("failed"), Done, Skipped, Skipped]);
+    }
+
+    #[test]
+    fn panicked() {
+        let mut dag = Dag::new();
+        let a = dag.add(|_: &mut Vec<usize>| panic!("boom"));
+        let b = dag.add(push(1));
+        dag.add(push(2));
+        dag.depend(b, a);
+        let (ran, outcomes) = run(dag);
+        assert_eq!(ran, [2]);
+        use Outcome::*;
+        assert_eq!(outcomes, [Panicked("boom".into()), Skipped, Done]);
+    }
+}
@@ -0,0 +1,97 @@
+//! Errors returned when joining the pool.
+
+use crate::Shared;
+use std::any::Any;
+
+/// A panic raised by a job.
+pub struct Panic {
+    /// The worker the job was running on.  Numbered from one as in `execute`.
+    ///
+    /// Zero is one of the helper threads like the timer thread.
+    pub worker: usize,
+    /// The payload given to `panic!`.
+    pub payload: Box<dyn Any + Send>,
+}
+
+impl Panic {
+    /// Return the panic message if the payload is a string.
+    pub fn message(&self) -> Option<&str> {
+        message(&*self.payload)
+    }
+}
+
+/// Return the message of a panic payload if it is a string.
+pub(crate) fn message(payload: &(dyn Any + Send)) -> Option<&str> {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|x| x.as_str()))
+}
+
+impl std::fmt::Debug for Panic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Panic")
+            .field("worker", &self.worker)
+            .field("message", &self.message())
+            .finish()
+    }
+}
+
+/// The error returned when jobs panicked.
+///
+/// The generic type R is the result of the workers that survived.
+pub struct JoinError<R> {
+    /// All panics in the order they were collected.
+    pub panics: Vec<Panic>,
+    /// The number of jobs that were still queued and never executed.
+    pub queued: usize,
+    /// The partial result.
+    pub partial: R,
+}
+
+impl<R> JoinError<R> {
+    /// Collect the panics of the jobs and drop the jobs nobody executed.
+    pub(crate) fn check<W, J>(
+        shared: &Shared<'_, W, J>,
+        workers: Vec<Panic>,
+        partial: R,
+    ) -> Result<R, Self> {
+        let mut panics = shared.take_panics();
+        panics.extend(workers);
+        let queued = shared.drain();
+        if panics.is_empty() {
+            return Ok(partial);
+        }
+        Err(Self {
+            panics,
+            queued,
+            partial,
+        })
+    }
+}
+
+impl<R> std::fmt::Debug for JoinError<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JoinError")
+            .field("panics", &self.panics)
+            .field("queued", &self.queued)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R> std::fmt::Display for JoinError<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} panic(s)", self.panics.len())?;
+        if let Some(first) = self.panics.first() {
+            write!(
+                f,
+                ", first on worker {}: {}",
+                first.worker,
+                first.message().unwrap_or("Box<dyn Any>")
+            )?;
+        }
+        write!(f, " - {} job(s) still queued", self.queued)
+    }
+}
+
+impl<R> std::error::Error for JoinError<R> {}
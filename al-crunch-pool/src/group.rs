@@ -0,0 +1,310 @@
+//! Groups of jobs that can be waited for and cancelled on their own.
+//!
+//! The group of the running job is kept in a thread-local, so that the jobs
+//! it sends through a plain `Sender` are counted against the group as well.
+
+use crate::{sim, CancelToken, Sender};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+thread_local! {
+    /// The group of the job running on this thread.
+    static CURRENT: RefCell<Option<Arc<Group>>> = const { RefCell::new(None) };
+}
+
+/// The state of a group shared with its jobs.
+pub(crate) struct Group {
+    /// The group of the job that created this one.
+    parent: Option<Arc<Group>>,
+    /// The number of jobs that have not finished yet.
+    pending: AtomicUsize,
+    /// The lock to wait on.
+    lock: Mutex<()>,
+    /// Wakes up the waiters when the last job finished.
+    done: Condvar,
+    /// Cancels the jobs of the group.
+    cancel: CancelToken,
+}
+
+impl Group {
+    /// Return the group and all its parents.
+    fn ancestors(&self) -> impl Iterator<Item = &Group> {
+        std::iter::successors(Some(self), |x| x.parent.as_deref())
+    }
+
+    /// Is the group or one of its parents cancelled?
+    fn is_cancelled(&self) -> bool {
+        self.ancestors().any(|x| x.cancel.is_cancelled())
+    }
+
+    /// Return the group of the running job.
+    pub(crate) fn current() -> Option<Arc<Self>> {
+        CURRENT.with_borrow(|x| x.clone())
+    }
+
+    /// Is the group of the running job cancelled?
+    pub(crate) fn current_is_cancelled() -> bool {
+        CURRENT.with_borrow(|x| x.as_ref().is_some_and(|x| x.is_cancelled()))
+    }
+
+    /// Count a job against the group and its parents.
+    pub(crate) fn join(self: Arc<Self>) -> Member {
+        for group in self.ancestors() {
+            group.pending.fetch_add(1, Ordering::SeqCst);
+        }
+        Member(self)
+    }
+}
+
+/// A job of a group.
+///
+/// Counts the job as finished when it is dropped, whether it ran or not.
+pub(crate) struct Member(Arc<Group>);
+
+impl Member {
+    /// Run the job in the group unless the group was cancelled in the meantime.
+    pub(crate) fn run(self, job: impl FnOnce()) {
+        let _enter = Enter::new(Some(self.0.clone()));
+        if !self.0.is_cancelled() {
+            job();
+        }
+    }
+}
+
+impl Drop for Member {
+    fn drop(&mut self) {
+        for group in self.0.ancestors() {
+            if group.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+                let _guard = group.lock.lock().unwrap();
+                group.done.notify_all();
+            }
+        }
+    }
+}
+
+/// Sets the group of the current thread and restores the previous one on drop.
+pub(crate) struct Enter(Option<Arc<Group>>);
+
+impl Enter {
+    pub(crate) fn new(group: Option<Arc<Group>>) -> Self {
+        Self(CURRENT.replace(group))
+    }
+}
+
+impl Drop for Enter {
+    fn drop(&mut self) {
+        CURRENT.set(self.0.take());
+    }
+}
+
+/// A group of jobs with its own completion and cancellation.
+///
+/// All jobs sent through the group and all jobs they send in turn are
+/// counted against it.  A group created within a job of another group is
+/// part of that group as well.
+///
+/// The group holds a sender, so the pool only joins once it is dropped.
+pub struct JobGroup<'a, W> {
+    sender: Sender<'a, W>,
+    group: Arc<Group>,
+}
+
+impl<'a, W> JobGroup<'a, W> {
+    /// Create an empty group.
+    pub(crate) fn new(sender: Sender<'a, W>) -> Self {
+        let group = Group {
+            parent: Group::current(),
+            pending: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            done: Condvar::new(),
+            cancel: CancelToken::default(),
+        };
+        Self {
+            sender,
+            group: Arc::new(group),
+        }
+    }
+
+    /// Send the job as part of the group.
+    ///
+    /// See `Sender::send`.
+    pub fn send<F>(&self, worker: &mut W, job: F)
+    where
+        F: FnOnce(&mut W) + Send + 'a,
+    {
+        let _enter = Enter::new(Some(self.group.clone()));
+        self.sender.send(worker, job);
+    }
+
+    /// Return the number of jobs of the group that have not finished yet.
+    pub fn pending(&self) -> usize {
+        self.group.pending.load(Ordering::SeqCst)
+    }
+
+    /// Return true if all jobs of the group have finished.
+    pub fn is_done(&self) -> bool {
+        self.pending() == 0
+    }
+
+    /// Block until all jobs of the group have finished.
+    ///
+    /// This blocks the thread.  A job waiting for a group may deadlock the
+    /// pool if all other workers do the same.
+    pub fn wait(&self) {
+        while self.pending() > 0 && sim::step() {}
+        let mut guard = self.group.lock.lock().unwrap();
+        while self.pending() > 0 {
+            guard = self.group.done.wait(guard).unwrap();
+        }
+    }
+
+    /// Cancel the jobs of the group.
+    ///
+    /// Queued jobs are dropped and new ones are ignored.  The other jobs of the pool keep running.
+    pub fn cancel(&self) {
+        self.group.cancel.cancel();
+    }
+
+    /// Return a handle to cancel the jobs of the group.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.group.cancel.clone()
+    }
+
+    /// Return true if the group or the group it was created in was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.group.is_cancelled()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Group;
+    use crate::{execute, execute_with_stats, Options};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Return a job counting itself after a while.
+    fn count(ran: &AtomicUsize) -> impl FnOnce(&mut ()) + Send + '_ {
+        move |_| {
+            std::thread::sleep(Duration::from_millis(10));
+            ran.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn transitive() {
+        let ran = AtomicUsize::new(0);
+        let options = Options::default().threads(Some(2));
+        execute(
+            options,
+            |_| (),
+            |x| x,
+            |s| {
+                let group = s.group();
+                let sender = s.clone();
+                let ran = &ran;
+                group.send(&mut (), move |state| {
+                    (0..3).for_each(|_| sender.send(state, count(ran)));
+                });
+                group.wait();
+                assert!(group.is_done());
+                assert_eq!(ran.load(Ordering::SeqCst), 3);
+            },
+            |_, _| (),
+        );
+    }
+
+    #[test]
+    fn inline() {
+        let ran = AtomicUsize::new(0);
+        let options = Options::default().threads(Some(1)).slots(1).stats();
+        let ((), stats) = execute_with_stats(
+            options,
+            |_| (),
+            |x| x,
+            |s| {
+                let group = s.group();
+                let sender = s.clone();
+                let ran = &ran;
+                group.send(&mut (), move |state| {
+                    sender.send(state, count(ran));
+                    let s = sender.clone();
+                    sender.send(state, move |state| {
+                        assert!(Group::current().is_some());
+                        s.send(state, count(ran));
+                    });
+                });
+                group.wait();
+                assert_eq!(ran.load(Ordering::SeqCst), 2);
+            },
+            |_, _| (),
+        );
+        assert_eq!(stats.total().inline, 2);
+    }
+
+    #[test]
+    fn nested() {
+        let ran = AtomicUsize::new(0);
+        let options = Options::default().threads(Some(2));
+        execute(
+            options,
+            |_| (),
+            |x| x,
+            |s| {
+                // the outer group waits for the jobs of the inner one
+                let outer = s.group();
+                let sender = s.clone();
+                let ran = &ran;
+                outer.send(&mut (), move |state| {
+                    let inner = sender.group();
+                    (0..3).for_each(|_| inner.send(state, count(ran)));
+                });
+                outer.wait();
+                assert_eq!(ran.load(Ordering::SeqCst), 3);
+
+                // cancelling the outer group cancels the inner one
+                let outer = s.group();
+                let token = outer.cancel_token();
+                let sender = s.clone();
+                outer.send(&mut (), move |state| {
+                    let inner = sender.group();
+                    token.cancel();
+                    assert!(inner.is_cancelled());
+                    inner.send(state, count(ran));
+                });
+                outer.wait();
+                assert_eq!(ran.load(Ordering::SeqCst), 3);
+                assert!(!s.group().is_cancelled());
+            },
+            |_, _| (),
+        );
+    }
+
+    #[test]
+    fn cancel() {
+        let ran = AtomicUsize::new(0);
+        let options = Options::default().threads(Some(1));
+        execute(
+            options,
+            |_| (),
+            |x| x,
+            |s| {
+                let group = s.group();
+                let token = group.cancel_token();
+                let sender = s.clone();
+                let ran = &ran;
+                group.send(&mut (), move |state| {
+                    (0..3).for_each(|_| sender.send(state, count(ran)));
+                    token.cancel();
+                    sender.send(state, count(ran));
+                });
+                s.send(&mut (), count(ran));
+                group.wait();
+                assert!(group.is_cancelled());
+            },
+            |_, _| (),
+        );
+        assert_eq!(ran.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,140 @@
+//! Handles to the results of the jobs.
+
+use crate::sim;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// The state of a result.
+enum State<T> {
+    /// The job has not finished yet.
+    Pending,
+    /// The job has returned a value.
+    Ready(T),
+    /// The value was taken or the job was dropped without running.
+    Done,
+}
+
+/// The result shared between the job and the handle.
+struct Slot<T> {
+    state: Mutex<State<T>>,
+    ready: Condvar,
+    /// Wakes up the task awaiting the handle.
+    waker: Mutex<Option<Waker>>,
+}
+
+/// A handle to the value returned by a spawned job.
+///
+/// It can be awaited as a future as well.
+pub struct JobHandle<T>(Arc<Slot<T>>);
+
+/// The side of the slot that is moved into the job.
+///
+/// Dropping it without a value marks the job as dropped.
+pub(crate) struct Promise<T>(Arc<Slot<T>>);
+
+/// Create a connected promise and handle.
+pub(crate) fn new<T>() -> (Promise<T>, JobHandle<T>) {
+    let slot = Arc::new(Slot {
+        state: Mutex::new(State::Pending),
+        ready: Condvar::new(),
+        waker: Mutex::new(None),
+    });
+    (Promise(slot.clone()), JobHandle(slot))
+}
+
+impl<T> Promise<T> {
+    /// Store the value returned by the job.
+    pub(crate) fn set(self, value: T) {
+        self.finish(State::Ready(value));
+    }
+
+    /// Leave the pending state and wake up the waiters.
+    fn finish(&self, new: State<T>) {
+        let mut state = self.0.state.lock().unwrap();
+        if matches!(*state, State::Pending) {
+            *state = new;
+            self.0.ready.notify_all();
+            if let Some(waker) = self.0.waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> Drop for Promise<T> {
+    fn drop(&mut self) {
+        self.finish(State::Done);
+    }
+}
+
+impl<T> JobHandle<T> {
+    /// Return true if the job has finished or was dropped.
+    pub fn is_finished(&self) -> bool {
+        !matches!(*self.0.state.lock().unwrap(), State::Pending)
+    }
+
+    /// Take the value without blocking.
+    ///
+    /// Returns `Ready(None)` if the job was dropped without running, because
+    /// it panicked or the pool was cancelled, or if the value was already taken.
+    pub fn try_take(&mut self) -> Poll<Option<T>> {
+        let mut state = self.0.state.lock().unwrap();
+        match std::mem::replace(&mut *state, State::Done) {
+            State::Pending => {
+                *state = State::Pending;
+                Poll::Pending
+            }
+            State::Ready(value) => Poll::Ready(Some(value)),
+            State::Done => Poll::Ready(None),
+        }
+    }
+
+    /// Block until the job has finished and return its value.
+    ///
+    /// Returns None if the job was dropped without running.  Jobs should
+    /// rather use `Sender::wait` to keep their worker busy while waiting.
+    pub fn wait(mut self) -> Option<T> {
+        while !self.is_finished() && sim::step() {}
+        {
+            let state = self.0.state.lock().unwrap();
+            let _state = self
+                .0
+                .ready
+                .wait_while(state, |x| matches!(x, State::Pending))
+                .unwrap();
+        }
+        match self.try_take() {
+            Poll::Ready(value) => value,
+            Poll::Pending => unreachable!(),
+        }
+    }
+
+    /// Block until the job has finished or the timeout expired.
+    pub(crate) fn wait_timeout(&self, timeout: Duration) {
+        let state = self.0.state.lock().unwrap();
+        let _ = self
+            .0
+            .ready
+            .wait_timeout_while(state, timeout, |x| matches!(x, State::Pending))
+            .unwrap();
+    }
+}
+
+impl<T> Future for JobHandle<T> {
+    type Output = Option<T>;
+
+    /// Returns None if the job was dropped without running.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let state = this.0.state.lock().unwrap();
+        if matches!(*state, State::Pending) {
+            *this.0.waker.lock().unwrap() = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        drop(state);
+        this.try_take()
+    }
+}
@@ -1,57 +1,248 @@
 //! A simple multi-threaded pool for executing number-chrunching workloads.
 
-/// Relying on crossbeam to make our life easier.
-use crossbeam::channel;
+mod adaptive;
+
+mod affinity;
+pub use affinity::{CpuSet, Placement};
+
+mod cgroup;
+pub use cgroup::{CpuQuota, Parallelism};
+
+mod cancel;
+pub use cancel::CancelToken;
+
+mod dag;
+pub use dag::{CycleError, Dag, Outcome, TaskId};
+
+mod error;
+pub use error::{JoinError, Panic};
+
+mod group;
+pub use group::JobGroup;
+
+mod handle;
+pub use handle::JobHandle;
 
 mod options;
-pub use options::Options;
+pub use options::{Options, Order};
+
+mod sched;
+pub use sched::{IoPriority, Policy, Scheduling};
 
 mod sender;
-pub use sender::Sender;
+pub use sender::{Handler, Sender};
+
+mod signal;
+pub use signal::SignalGuard;
+
+mod sim;
+
+mod stats;
+pub use stats::{Stats, WorkerStats};
+
+mod par;
+pub use par::{par_for_each, par_map, par_reduce, ParOptions, Source};
+
+mod priority;
+
+mod progress;
+pub use progress::{Progress, ProgressCounter, ProgressReport};
+
+mod pipeline;
+pub use pipeline::{Emit, Pipeline, Stage};
 
 mod pool;
 pub use pool::Pool;
 
+mod queue;
+use queue::Shared;
+
+mod task;
+pub use task::StateRef;
+
+mod timer;
+
+mod worker;
+pub use worker::WorkerInfo;
+use worker::{Spawn, Workers};
+
+use std::sync::Arc;
+
 /// Execute the jobs in a scoped pool.
 ///
-/// create: a closure to create per-worker states
+/// create: a closure to create per-worker states on the worker threads
 /// init: a closure to submit the first jobs.
 /// combine: a closure to combine all states into one result
-pub fn execute<W, Y: Send, X>(
+///
+/// The jobs do not need to be `'static`.  They may borrow from the stack
+/// frame of the caller as they have all finished when this function returns.
+///
+/// Panics if a job panicked.  See `try_execute` for handling the panics.
+pub fn execute<'env, W, Y: Send, X>(
     options: Options,
-    create: impl Fn(usize) -> W + Send + Copy,
+    create: impl Fn(WorkerInfo) -> W + Send + Copy,
     destroy: impl Fn(W) -> Y + Send + Copy,
-    init: impl FnOnce(&Sender<W>) -> X,
+    init: impl FnOnce(&Sender<'env, W>) -> X,
     combine: impl Fn(X, Y) -> X,
 ) -> X {
-    let threads = options.get_threads();
+    try_execute(options, create, destroy, init, combine).unwrap_or_else(|e| panic!("{e}"))
+}
 
-    // the bounded Job queue
-    let (sender, receiver) = channel::bounded::<sender::SenderFunction<W>>(threads * options.slots);
+/// Execute the tasks of the graph in a scoped pool.
+///
+/// A task is sent once all its dependencies have succeeded.  The dependents
+/// of a failed task are skipped.  Tasks may run synchronously on the calling
+/// thread with a state created for worker zero.  Its result is combined
+/// with `init` first.
+///
+/// Returns the combined states and the outcome per task or an error before
+/// running anything if the dependencies have a cycle.  Panics if a task
+/// panicked like `execute` unless `catch_panics` is set.  The panics are then
+/// reported as `Outcome::Panicked` and the dependents are skipped.
+pub fn execute_dag<'env, W: 'env, Y: Send, X, E: Send + 'env>(
+    options: Options,
+    create: impl Fn(WorkerInfo) -> W + Send + Copy,
+    destroy: impl Fn(W) -> Y + Send + Copy,
+    dag: Dag<'env, W, E>,
+    init: X,
+    combine: impl Fn(X, Y) -> X,
+) -> Result<(X, Vec<Outcome<E>>), CycleError> {
+    let schedule = Arc::new(dag.schedule(options.catch_panics)?);
+    let res = execute(
+        options,
+        create,
+        destroy,
+        |sender| {
+            let mut state = create(WorkerInfo::caller());
+            schedule.start(sender, &mut state);
+            combine(init, destroy(state))
+        },
+        &combine,
+    );
+    let schedule = Arc::into_inner(schedule).expect("all tasks have finished");
+    Ok((res, schedule.outcomes()))
+}
+
+/// Execute the jobs in a scoped pool and return the statistics as well.
+///
+/// Panics if a job panicked.  See `Options::stats`.
+pub fn execute_with_stats<'env, W, Y: Send, X>(
+    options: Options,
+    create: impl Fn(WorkerInfo) -> W + Send + Copy,
+    destroy: impl Fn(W) -> Y + Send + Copy,
+    init: impl FnOnce(&Sender<'env, W>) -> X,
+    combine: impl Fn(X, Y) -> X,
+) -> (X, Stats) {
+    let (res, stats) = scoped(options, create, destroy, init, combine);
+    (res.unwrap_or_else(|e| panic!("{e}")), stats)
+}
+
+/// Execute the jobs in a scoped pool and return the panics of the jobs.
+///
+/// The partial result of the error combines the states of the workers that survived.
+pub fn try_execute<'env, W, Y: Send, X>(
+    options: Options,
+    create: impl Fn(WorkerInfo) -> W + Send + Copy,
+    destroy: impl Fn(W) -> Y + Send + Copy,
+    init: impl FnOnce(&Sender<'env, W>) -> X,
+    combine: impl Fn(X, Y) -> X,
+) -> Result<X, JoinError<X>> {
+    scoped(options, create, destroy, init, combine).0
+}
+
+/// Stops the timer thread and the progress reporter even if `init` panicked.
+///
+/// The scope would wait for the reporter forever otherwise and the timer
+/// thread must not outlive the jobs.
+struct Helpers<'s, 'a, W>(&'s Shared<'a, W>);
+
+impl<W> Drop for Helpers<'_, '_, W> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            let _ = self.0.timers.close();
+            self.0.progress.close();
+        }
+    }
+}
+
+/// Run the scoped pool and collect the statistics.
+fn scoped<'env, W, Y: Send, X>(
+    options: Options,
+    create: impl Fn(WorkerInfo) -> W + Send + Copy,
+    destroy: impl Fn(W) -> Y + Send + Copy,
+    init: impl FnOnce(&Sender<'env, W>) -> X,
+    combine: impl Fn(X, Y) -> X,
+) -> (Result<X, JoinError<X>>, Stats) {
+    if let Some((seed, workers)) = options.simulation {
+        return sim::simulate(seed, workers, options, create, destroy, init, combine);
+    }
+    let infos = options.get_workers().expect("placing the workers");
+
+    // the work-stealing job queues
+    let shared = Shared::new(infos.len(), &options);
 
     std::thread::scope(|s| {
         // create the worker threads
-        let worker: Vec<std::thread::ScopedJoinHandle<'_, _>> = (0..threads)
-            .map(|i| {
-                let receiver = receiver.clone();
-                s.spawn(move || {
-                    let mut state = create(i + 1);
-                    for job in receiver {
-                        job(&mut state);
-                    }
-
-                    destroy(state)
-                })
-            })
-            .collect();
+        let spawn: Spawn<'_, _, _> = Box::new({
+            let shared = shared.clone();
+            let options = options.clone();
+            move |info: WorkerInfo, local| {
+                let shared = shared.clone();
+                options
+                    .thread(info.index)
+                    .spawn_scoped(s, move || {
+                        let state = shared.run(info.index, local, || {
+                            info.setup();
+                            create(info)
+                        });
+                        destroy(state)
+                    })
+                    .expect("spawning a worker")
+            }
+        });
+        let threads = options.get_threads();
+        let workers = Arc::new(Workers::new(shared.clone(), infos, threads, spawn));
+        let monitor = options.adaptive.then(|| {
+            let workers = workers.clone();
+            options
+                .thread("monitor")
+                .spawn_scoped(s, move || adaptive::monitor(&workers, threads))
+                .expect("spawning the monitor")
+        });
+
+        let reporter = shared.progress.is_enabled().then(|| {
+            options
+                .thread("progress")
+                .spawn_scoped(s, || shared.progress.run())
+                .expect("spawning the progress reporter")
+        });
 
         // submit the initial jobs
-        let state = init(&Sender(sender));
+        let helpers = Helpers(&shared);
+        let mut state = init(&Sender::new(shared.clone()));
+        drop(helpers);
 
         // combine all results
-        worker
-            .into_iter()
-            .map(|w| w.join().unwrap())
-            .fold(state, combine)
+        let mut panics = Vec::new();
+        if let Err(payload) = shared.close_timers() {
+            panics.push(Panic { worker: 0, payload });
+        }
+        shared.progress.close();
+        if let Some(Err(payload)) = reporter.map(|x| x.join()) {
+            panics.push(Panic { worker: 0, payload });
+        }
+        if let Some(monitor) = monitor {
+            monitor.thread().unpark();
+            if let Err(payload) = monitor.join() {
+                panics.push(Panic { worker: 0, payload });
+            }
+        }
+        for (worker, w) in workers.take_handles() {
+            match w.join() {
+                Ok(y) => state = combine(state, y),
+                Err(payload) => panics.push(Panic { worker, payload }),
+            }
+        }
+        (JoinError::check(&shared, panics, state), shared.stats())
     })
 }
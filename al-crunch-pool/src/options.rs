@@ -1,5 +1,21 @@
 //! Configuration options for the pool.
 
+use crate::{IoPriority, Parallelism, Placement, Policy, Progress, Scheduling, WorkerInfo};
+
+/// The order in which the queued jobs are executed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Order {
+    /// Oldest job first.  This expands a tree breadth-first.
+    #[default]
+    Fifo,
+    /// Newest job first.  This expands a tree depth-first and keeps less jobs pending.
+    Lifo,
+    /// Highest priority first as given to `Sender::send_with_priority`.
+    ///
+    /// All jobs go through a single locked queue.  `Sender::send` uses priority zero.
+    Priority,
+}
+
 /// Collect the config options to make a pool.
 #[derive(Clone, Debug)]
 pub struct Options {
@@ -7,28 +23,105 @@ pub struct Options {
     pub(crate) slots: usize,
     one_is_zero: bool,
     io_bound: bool,
+    pub(crate) catch_panics: bool,
+    pub(crate) order: Order,
+    placement: Placement,
+    max_threads: Option<usize>,
+    pub(crate) adaptive: bool,
+    pub(crate) stats: bool,
+    thread_name: Option<String>,
+    stack_size: Option<usize>,
+    pub(crate) progress: Option<Progress>,
+    pub(crate) cancel_on_signals: bool,
+    scheduling: Scheduling,
+    pub(crate) simulation: Option<(u64, usize)>,
 }
 
 impl Options {
     /// Get the number of threads.
+    ///
+    /// Without an explicit number this is one thread per CPU in the affinity
+    /// mask limited by the CPU quota of the cgroups.  They are detected once
+    /// per process.  See `Parallelism::cached`.
     pub fn get_threads(&self) -> usize {
-        match self.threads.unwrap_or(
-            std::thread::available_parallelism()
-                .map(|x| x.get())
-                .unwrap_or(0),
-        ) {
+        match self.threads.unwrap_or_else(|| Parallelism::cached().get()) {
             1 if self.one_is_zero => 0,
             x if self.io_bound => 4 * x,
             x => x,
         }
     }
 
-    /// Set the number of threads.  None means one thread per core.
+    /// Get the maximum number of threads when resizing the pool.
+    pub fn get_max_threads(&self) -> usize {
+        let threads = self.get_threads();
+        let default = if self.adaptive { 4 * threads } else { threads };
+        self.max_threads.unwrap_or(default).max(threads)
+    }
+
+    /// Get all workers the pool may start with their CPUs.
+    ///
+    /// Fails if the placement cannot be determined.
+    pub fn get_workers(&self) -> std::io::Result<Vec<WorkerInfo>> {
+        let cpus = self.placement.resolve(self.get_max_threads())?;
+        Ok(cpus
+            .into_iter()
+            .enumerate()
+            .map(|(i, cpus)| WorkerInfo {
+                index: i + 1,
+                cpus,
+                scheduling: self.scheduling,
+            })
+            .collect())
+    }
+
+    /// Get a builder for a thread of the pool.
+    ///
+    /// The name is the prefix followed by the suffix.
+    pub(crate) fn thread(&self, suffix: impl std::fmt::Display) -> std::thread::Builder {
+        let mut builder = std::thread::Builder::new();
+        if let Some(prefix) = &self.thread_name {
+            builder = builder.name(format!("{prefix}-{suffix}"));
+        }
+        if let Some(size) = self.stack_size {
+            builder = builder.stack_size(size);
+        }
+        builder
+    }
+
+    /// Set the number of threads.  None means one thread per available core.
     pub fn threads(self, threads: Option<usize>) -> Self {
         Self { threads, ..self }
     }
 
+    /// Set the maximum number of threads when resizing the pool.
+    ///
+    /// None means that the pool does not grow beyond its initial size, or
+    /// up to four times that with `adaptive`.
+    pub fn max_threads(self, max_threads: Option<usize>) -> Self {
+        Self {
+            max_threads,
+            ..self
+        }
+    }
+
+    /// Adapt the number of workers to the load.
+    ///
+    /// The pool grows up to `max_threads`, by default four times the initial
+    /// threads like with `io_bound`, while most workers are blocked in
+    /// syscalls and shrinks back to the initial number of threads when the
+    /// CPUs are saturated.  This is an alternative to `io_bound` for devices
+    /// with unknown latency.
+    pub fn adaptive(self) -> Self {
+        Self {
+            adaptive: true,
+            ..self
+        }
+    }
+
     /// Define the slots per thread.
+    ///
+    /// A job is executed synchronously when the local queue of the sending
+    /// worker already holds that many jobs.
     pub fn slots(self, slots: usize) -> Self {
         Self { slots, ..self }
     }
@@ -41,7 +134,34 @@ impl Options {
         }
     }
 
+    /// Simulate the workers on the calling thread in a random order given by the seed.
+    ///
+    /// There are that many virtual workers with their own states and a local
+    /// queue of `slots` jobs, so that jobs are still executed synchronously
+    /// when a queue is full.  The number of threads is ignored as it depends
+    /// on the machine, so the same seed runs the jobs in the same order
+    /// everywhere.  Unlike `one_is_zero` this keeps the interleaving of the
+    /// jobs, which makes it useful to test the combine logic.
+    ///
+    /// This applies to `execute` and the functions built on it.  There are no
+    /// timers, so `Sender::send_after` and `Sender::send_every` drop their jobs.
+    /// Panics of the jobs unwind unless `catch_panics` is set.
+    ///
+    /// Blocking waits like `JobHandle::wait` and `JobGroup::wait` run the
+    /// queued jobs on the virtual workers that are not blocked themselves.
+    /// They panic if no worker is left to run them, where a pool with that
+    /// many threads would deadlock.
+    pub fn simulate(self, seed: u64, workers: usize) -> Self {
+        Self {
+            simulation: Some((seed, workers)),
+            ..self
+        }
+    }
+
     /// Indicates that the work is depending on external I/O and more threads should be allocated.
+    ///
+    /// Combine it with `io_priority` and `policy` to keep the additional
+    /// threads out of the way of other processes.
     pub fn io_bound(self) -> Self {
         Self {
             io_bound: true,
@@ -49,6 +169,108 @@ impl Options {
         }
     }
 
+    /// Catch the panics of the jobs so that the worker state survives.
+    ///
+    /// The panics are reported when the pool joins.
+    pub fn catch_panics(self) -> Self {
+        Self {
+            catch_panics: true,
+            ..self
+        }
+    }
+
+    /// Define the order in which the queued jobs are executed.
+    ///
+    /// Jobs sent with a priority always go ahead of the others.
+    pub fn order(self, order: Order) -> Self {
+        Self { order, ..self }
+    }
+
+    /// Collect statistics about the jobs and the workers.
+    ///
+    /// This measures the time of every job.  See `Stats`.
+    pub fn stats(self) -> Self {
+        Self {
+            stats: true,
+            ..self
+        }
+    }
+
+    /// Name the threads by the prefix and the index of the worker.
+    ///
+    /// Linux shows only the first 15 bytes, so the prefix should be short.
+    pub fn thread_name(self, prefix: impl Into<String>) -> Self {
+        Self {
+            thread_name: Some(prefix.into()),
+            ..self
+        }
+    }
+
+    /// Set the stack size of the threads in bytes.
+    pub fn stack_size(self, size: usize) -> Self {
+        Self {
+            stack_size: Some(size),
+            ..self
+        }
+    }
+
+    /// Report the progress counters while the pool runs.
+    ///
+    /// A reporter thread is started that stops when the pool joins.
+    pub fn progress(self, progress: Progress) -> Self {
+        Self {
+            progress: Some(progress),
+            ..self
+        }
+    }
+
+    /// Cancel the jobs on SIGINT and SIGTERM.
+    ///
+    /// The queued jobs are dropped and the workers finish so that their
+    /// states are still destroyed when the pool joins.  A second signal
+    /// terminates the process.  The previous handlers are restored when the
+    /// pool is gone.  See `CancelToken::cancel_on_signals`.
+    pub fn cancel_on_signals(self) -> Self {
+        Self {
+            cancel_on_signals: true,
+            ..self
+        }
+    }
+
+    /// Set the nice value of the workers from -20 to 19.
+    ///
+    /// Lowering it below the value of the process needs privileges.
+    pub fn nice(self, nice: i32) -> Self {
+        let scheduling = Scheduling {
+            nice: Some(nice),
+            ..self.scheduling
+        };
+        Self { scheduling, ..self }
+    }
+
+    /// Set the scheduling policy of the workers.
+    pub fn policy(self, policy: Policy) -> Self {
+        let scheduling = Scheduling {
+            policy,
+            ..self.scheduling
+        };
+        Self { scheduling, ..self }
+    }
+
+    /// Set the I/O priority of the workers.
+    pub fn io_priority(self, io: IoPriority) -> Self {
+        let scheduling = Scheduling {
+            io: Some(io),
+            ..self.scheduling
+        };
+        Self { scheduling, ..self }
+    }
+
+    /// Define on which CPUs the workers are placed.
+    pub fn placement(self, placement: Placement) -> Self {
+        Self { placement, ..self }
+    }
+
     /// Build the pool directly from the options if the worker state implements Default.
     pub fn build<W: Default + 'static>(self) -> crate::Pool<W, ()> {
         crate::Pool::new(self, (), |_| Default::default(), |_| ())
@@ -62,6 +284,18 @@ impl Default for Options {
             slots: 8,
             one_is_zero: false,
             io_bound: false,
+            catch_panics: false,
+            order: Order::Fifo,
+            placement: Placement::Any,
+            max_threads: None,
+            adaptive: false,
+            stats: false,
+            thread_name: None,
+            stack_size: None,
+            progress: None,
+            cancel_on_signals: false,
+            scheduling: Scheduling::default(),
+            simulation: None,
         }
     }
 }
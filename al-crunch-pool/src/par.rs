@@ -0,0 +1,297 @@
+//! Parallel iterators on top of `execute`.
+//!
+//! The input is split in halves until the chunks are small enough.  One half
+//! is sent to the pool while the other one is split further on the same worker.
+
+use crate::{execute, Options, Sender, WorkerInfo};
+use std::ops::Range;
+use std::sync::Mutex;
+
+/// An input that can be split into chunks.
+pub trait Source: Sync {
+    /// The items of the input.
+    type Item;
+
+    /// Return the number of items.
+    fn size(&self) -> usize;
+
+    /// Return the item at the index.
+    fn at(&self, index: usize) -> Self::Item;
+}
+
+impl<'a, T: Sync> Source for &'a [T] {
+    type Item = &'a T;
+
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn at(&self, index: usize) -> Self::Item {
+        &self[index]
+    }
+}
+
+impl Source for Range<usize> {
+    type Item = usize;
+
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn at(&self, index: usize) -> Self::Item {
+        self.start + index
+    }
+}
+
+/// Options of the parallel iterators.
+///
+/// Plain `Options` convert into this with the default chunking.
+#[derive(Clone, Debug, Default)]
+pub struct ParOptions {
+    options: Options,
+    chunk_size: Option<usize>,
+    preserve_order: bool,
+}
+
+impl ParOptions {
+    /// Use the options for the pool.
+    pub fn new(options: Options) -> Self {
+        Self {
+            options,
+            ..Self::default()
+        }
+    }
+
+    /// Set the number of items per job.
+    pub fn chunk_size(self, size: usize) -> Self {
+        Self {
+            chunk_size: Some(size),
+            ..self
+        }
+    }
+
+    /// Let `par_map` return the values in the order of the input.
+    pub fn preserve_order(self) -> Self {
+        Self {
+            preserve_order: true,
+            ..self
+        }
+    }
+
+    /// Get the number of items per job.
+    ///
+    /// Without an explicit size the input is split into four chunks per thread.
+    pub fn get_chunk_size(&self, len: usize) -> usize {
+        let chunks = 4 * self.options.get_threads().max(1);
+        self.chunk_size.unwrap_or(len.div_ceil(chunks)).max(1)
+    }
+}
+
+impl From<Options> for ParOptions {
+    fn from(options: Options) -> Self {
+        Self::new(options)
+    }
+}
+
+/// Split the range and run the chunks.
+fn split<'env, W: 'env, F>(
+    sender: &Sender<'env, W>,
+    state: &mut W,
+    mut range: Range<usize>,
+    chunk: usize,
+    f: &'env F,
+) where
+    F: Fn(&mut W, Range<usize>) + Sync,
+{
+    while range.len() > chunk {
+        let mid = range.start + range.len() / 2;
+        let right = mid..range.end;
+        range.end = mid;
+        let sender2 = sender.clone();
+        sender.send(state, move |state| split(&sender2, state, right, chunk, f));
+    }
+    f(state, range);
+}
+
+/// Run the function on the chunks of the input.
+///
+/// The calling thread gets a state for worker zero as the first chunk may run
+/// synchronously.
+fn chunks<'env, W: 'env, Y: Send, X>(
+    options: ParOptions,
+    len: usize,
+    create: impl Fn(WorkerInfo) -> W + Send + Copy,
+    destroy: impl Fn(W) -> Y + Send + Copy,
+    f: &'env (impl Fn(&mut W, Range<usize>) + Sync),
+    init: X,
+    combine: impl Fn(X, Y) -> X,
+) -> X {
+    let chunk = options.get_chunk_size(len);
+    execute(
+        options.options,
+        create,
+        destroy,
+        |sender| {
+            let mut state = create(WorkerInfo::caller());
+            split(sender, &mut state, 0..len, chunk, f);
+            combine(init, destroy(state))
+        },
+        &combine,
+    )
+}
+
+/// Call the function on every item with the state of the worker.
+///
+/// The states are destroyed and combined as in `execute`.
+pub fn par_for_each<S: Source, W, Y: Send, X>(
+    options: impl Into<ParOptions>,
+    source: S,
+    create: impl Fn(WorkerInfo) -> W + Send + Copy,
+    destroy: impl Fn(W) -> Y + Send + Copy,
+    f: impl Fn(&mut W, S::Item) + Sync,
+    init: X,
+    combine: impl Fn(X, Y) -> X,
+) -> X {
+    let job = |state: &mut W, range: Range<usize>| range.for_each(|i| f(state, source.at(i)));
+    chunks(
+        options.into(),
+        source.size(),
+        create,
+        destroy,
+        &job,
+        init,
+        combine,
+    )
+}
+
+/// Map every item with the state of the worker and collect the values.
+///
+/// The values are in the order of the input with `ParOptions::preserve_order`
+/// and in the order the chunks finished otherwise.
+pub fn par_map<S: Source, W, R: Send>(
+    options: impl Into<ParOptions>,
+    source: S,
+    create: impl Fn(WorkerInfo) -> W + Send + Copy,
+    f: impl Fn(&mut W, S::Item) -> R + Sync,
+) -> Vec<R> {
+    let options = options.into();
+    let preserve_order = options.preserve_order;
+    let values = Mutex::new(Vec::new());
+    let job = |state: &mut W, range: Range<usize>| {
+        let start = range.start;
+        let chunk: Vec<_> = range.map(|i| f(state, source.at(i))).collect();
+        values.lock().unwrap().push((start, chunk));
+    };
+    chunks(options, source.size(), create, drop, &job, (), |_, _| ());
+    let mut values = values.into_inner().unwrap();
+    if preserve_order {
+        values.sort_unstable_by_key(|x| x.0);
+    }
+    values.into_iter().flat_map(|x| x.1).collect()
+}
+
+/// Map every item with the state of the worker and reduce the values.
+///
+/// The values are reduced in the order of the input, so that the result is
+/// deterministic as long as `reduce` is associative.  Returns None if the
+/// input is empty.
+pub fn par_reduce<S: Source, W, R: Send>(
+    options: impl Into<ParOptions>,
+    source: S,
+    create: impl Fn(WorkerInfo) -> W + Send + Copy,
+    map: impl Fn(&mut W, S::Item) -> R + Sync,
+    reduce: impl Fn(R, R) -> R + Sync,
+) -> Option<R> {
+    let values = Mutex::new(Vec::new());
+    let job = |state: &mut W, range: Range<usize>| {
+        let start = range.start;
+        let value = range.map(|i| map(state, source.at(i))).reduce(&reduce);
+        values.lock().unwrap().extend(value.map(|x| (start, x)));
+    };
+    chunks(
+        options.into(),
+        source.size(),
+        create,
+        drop,
+        &job,
+        (),
+        |_, _| (),
+    );
+    let mut values = values.into_inner().unwrap();
+    values.sort_unstable_by_key(|x| x.0);
+    values.into_iter().map(|x| x.1).reduce(reduce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{par_for_each, par_map, par_reduce, ParOptions};
+    use crate::Options;
+    use std::time::Duration;
+
+    /// Return options splitting the input into many small chunks.
+    fn options() -> ParOptions {
+        ParOptions::new(Options::default().threads(Some(2))).chunk_size(3)
+    }
+
+    /// Sleep on some items so that the chunks finish out of order.
+    fn delay(i: usize) {
+        if i.is_multiple_of(7) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn preserve_order() {
+        let input: Vec<_> = (0..100).collect();
+        let values = par_map(
+            options().preserve_order(),
+            &input[..],
+            |_| (),
+            |_, x| {
+                delay(*x);
+                2 * x
+            },
+        );
+        assert_eq!(values, (0..100).map(|x| 2 * x).collect::<Vec<_>>());
+
+        let mut values = par_map(options(), 0..100, |_| (), |_, x| x);
+        values.sort();
+        assert_eq!(values, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reduce_in_order() {
+        let text = par_reduce(
+            options(),
+            0..20,
+            |_| (),
+            |_, x| {
+                delay(x);
+                x.to_string()
+            },
+            |a, b| format!("{a},{b}"),
+        );
+        let expected: Vec<_> = (0..20).map(|x| x.to_string()).collect();
+        assert_eq!(text, Some(expected.join(",")));
+
+        let sum = par_for_each(
+            options(),
+            0..100,
+            |_| 0,
+            |x| x,
+            |sum, x| *sum += x,
+            0,
+            |a, b| a + b,
+        );
+        assert_eq!(sum, 4950);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(
+            par_reduce(options(), 0..0, |_| (), |_, x| x, |a, b| a + b),
+            None
+        );
+        assert!(par_map(options(), 0..0, |_| (), |_, x| x).is_empty());
+    }
+}
@@ -0,0 +1,289 @@
+//! Stages of scoped pools connected by bounded queues.
+//!
+//! Every stage runs its own pool.  A feeding thread takes the items from the
+//! queue in front of the stage and sends them as jobs.  When the pool is
+//! saturated the feeding thread runs the jobs itself and stops taking items,
+//! so that the queue fills up and the previous stage blocks when emitting.
+
+use crate::{execute, Options, Sender, WorkerInfo};
+use crossbeam::channel::{bounded, Receiver, Sender as Output};
+use std::panic::resume_unwind;
+use std::thread::{Scope, ScopedJoinHandle};
+
+/// The function handling the items of a stage.
+type Handler<'env, W, I, O> = dyn Fn(&Emit<'_, '_, W, I, O>, &mut W, I) + Send + Sync + 'env;
+
+/// Lets the handler of a stage send items to its own stage or emit them to the next one.
+pub struct Emit<'s, 'p, W, I, O> {
+    sender: &'s Sender<'p, W>,
+    handler: &'p Handler<'p, W, I, O>,
+    output: &'p Output<O>,
+}
+
+impl<'p, W: 'p, I: Send + 'p, O: Send> Emit<'_, 'p, W, I, O> {
+    /// Send an item to the handler of this stage.
+    ///
+    /// It is queued or handled synchronously like a job sent with `Sender::send`.
+    pub fn send(&self, state: &mut W, item: I) {
+        submit(self.sender, self.handler, self.output, state, item);
+    }
+
+    /// Emit an item to the next stage.
+    ///
+    /// Blocks while the queue of the next stage is full.  The item is dropped
+    /// if the next stage is gone.
+    pub fn emit(&self, item: O) {
+        let _ = self.output.send(item);
+    }
+
+    /// Return the sender of the pool of this stage.
+    pub fn sender(&self) -> &Sender<'p, W> {
+        self.sender
+    }
+}
+
+/// Send the item as a job to the pool of the stage.
+fn submit<'p, W: 'p, I: Send + 'p, O: Send>(
+    sender: &Sender<'p, W>,
+    handler: &'p Handler<'p, W, I, O>,
+    output: &'p Output<O>,
+    state: &mut W,
+    item: I,
+) {
+    let sender2 = sender.clone();
+    sender.send(state, move |state| {
+        let emit = Emit {
+            sender: &sender2,
+            handler,
+            output,
+        };
+        handler(&emit, state, item)
+    });
+}
+
+/// A stage of a pipeline that turns items of type I into items of type O.
+///
+/// The worker-states of type W are combined into X when the stage finishes.
+pub struct Stage<'env, W, I, O, X> {
+    options: Options,
+    capacity: usize,
+    create: Box<dyn Fn(WorkerInfo) -> W + Send + Sync + 'env>,
+    handler: Box<Handler<'env, W, I, O>>,
+    init: X,
+    combine: Box<dyn Fn(X, W) -> X + Send + 'env>,
+}
+
+impl<'env, W: Send + 'env, I: Send + 'env, O: Send + 'env> Stage<'env, W, I, O, ()> {
+    /// Create a stage running a pool with the options.
+    ///
+    /// The queue in front of the stage holds as many items as the pool has
+    /// slots.  The states are dropped unless they are combined.
+    pub fn new(
+        options: Options,
+        create: impl Fn(WorkerInfo) -> W + Send + Sync + 'env,
+        handler: impl Fn(&Emit<'_, '_, W, I, O>, &mut W, I) + Send + Sync + 'env,
+    ) -> Self {
+        let capacity = options.slots * options.get_threads().max(1);
+        Self {
+            options,
+            capacity,
+            create: Box::new(create),
+            handler: Box::new(handler),
+            init: (),
+            combine: Box::new(|_, _| ()),
+        }
+    }
+}
+
+impl<'env, W: Send + 'env, I: Send + 'env, O: Send + 'env, X: Send + 'env> Stage<'env, W, I, O, X> {
+    /// Set the number of items the queue in front of the stage holds.
+    pub fn capacity(self, capacity: usize) -> Self {
+        Self { capacity, ..self }
+    }
+
+    /// Combine the worker-states when the stage finishes.
+    ///
+    /// This includes the state of the feeding thread.
+    pub fn combine<Y: Send + 'env>(
+        self,
+        init: Y,
+        combine: impl Fn(Y, W) -> Y + Send + 'env,
+    ) -> Stage<'env, W, I, O, Y> {
+        Stage {
+            options: self.options,
+            capacity: self.capacity,
+            create: self.create,
+            handler: self.handler,
+            init,
+            combine: Box::new(combine),
+        }
+    }
+
+    /// Run the stage until the input is closed and all jobs have finished.
+    fn run(self, input: Receiver<I>, output: Output<O>) -> X {
+        let Self {
+            options,
+            create,
+            handler,
+            init,
+            combine,
+            ..
+        } = self;
+        let (create, handler, output) = (&*create, &*handler, &output);
+        execute(
+            options,
+            create,
+            |x| x,
+            |sender| {
+                let mut state = create(WorkerInfo::caller());
+                for item in input {
+                    submit(sender, handler, output, &mut state, item);
+                }
+                combine(init, state)
+            },
+            &combine,
+        )
+    }
+
+    /// Start the stage on its own thread.
+    fn spawn<'s>(
+        self,
+        s: &'s Scope<'s, 'env>,
+        input: Receiver<I>,
+        output: Output<O>,
+    ) -> ScopedJoinHandle<'s, X> {
+        self.options
+            .thread(0)
+            .spawn_scoped(s, move || self.run(input, output))
+            .expect("spawning a stage")
+    }
+}
+
+/// Start the stages on the scope and return a function joining them.
+type Start<'env, I, O, R> = Box<
+    dyn for<'s> FnOnce(&'s Scope<'s, 'env>, Receiver<I>, Output<O>) -> Box<dyn FnOnce() -> R + 's>
+        + 'env,
+>;
+
+/// Join a stage and forward its panic.
+fn join<X>(handle: ScopedJoinHandle<'_, X>) -> X {
+    handle.join().unwrap_or_else(|e| resume_unwind(e))
+}
+
+/// Stages connected by bounded queues that turn items of type I into items of type O.
+///
+/// The generic type R holds the combined states of the stages.  They are
+/// nested in pairs, for example `((walk, read), hash)` for three stages.
+pub struct Pipeline<'env, I, O, R> {
+    /// The capacity of the queues in front of the first stage and the sink.
+    capacity: usize,
+    start: Start<'env, I, O, R>,
+}
+
+impl<'env, I: Send + 'env, O: Send + 'env, R: Send + 'env> Pipeline<'env, I, O, R> {
+    /// Create a pipeline with a single stage.
+    pub fn new<W: Send + 'env>(stage: Stage<'env, W, I, O, R>) -> Self {
+        Self {
+            capacity: stage.capacity,
+            start: Box::new(move |s, input, output| {
+                let handle = stage.spawn(s, input, output);
+                Box::new(move || join(handle))
+            }),
+        }
+    }
+
+    /// Append a stage that takes the items emitted by the previous one.
+    pub fn then<W: Send + 'env, P: Send + 'env, X: Send + 'env>(
+        self,
+        stage: Stage<'env, W, O, P, X>,
+    ) -> Pipeline<'env, I, P, (R, X)> {
+        let start = self.start;
+        Pipeline {
+            capacity: self.capacity,
+            start: Box::new(move |s, input, output| {
+                let (tx, rx) = bounded(stage.capacity);
+                let previous = start(s, input, tx);
+                let handle = stage.spawn(s, rx, output);
+                Box::new(move || (previous(), join(handle)))
+            }),
+        }
+    }
+
+    /// Feed the inputs through the stages and pass the outputs to the sink.
+    ///
+    /// The sink runs on the calling thread and blocks the last stage while it
+    /// is busy.  Returns the combined states when all stages have finished.
+    /// Panics if a stage panicked.
+    pub fn run<T>(self, inputs: T, mut sink: impl FnMut(O)) -> R
+    where
+        T: IntoIterator<Item = I>,
+        T::IntoIter: Send + 'env,
+    {
+        let (input, rx) = bounded(self.capacity);
+        let (tx, output) = bounded(self.capacity);
+        let inputs = inputs.into_iter();
+        std::thread::scope(|s| {
+            let feed = s.spawn(move || {
+                for item in inputs {
+                    if input.send(item).is_err() {
+                        break;
+                    }
+                }
+            });
+            let finish = (self.start)(s, rx, tx);
+            for item in output {
+                sink(item);
+            }
+            join(feed);
+            finish()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pipeline, Stage};
+    use crate::Options;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn backpressure() {
+        let emitted = AtomicUsize::new(0);
+        let options = || Options::default().threads(Some(1)).slots(1);
+        let even_squares = Stage::new(
+            options(),
+            |_| 0,
+            |emit, count: &mut usize, x: u64| {
+                *count += 1;
+                if x.is_multiple_of(2) {
+                    emitted.fetch_add(1, Ordering::SeqCst);
+                    emit.emit(x * x);
+                }
+            },
+        );
+        let double = Stage::new(
+            options(),
+            |_| 0,
+            |emit, count: &mut usize, x: u64| {
+                *count += 1;
+                emit.emit(2 * x);
+            },
+        );
+        let pipeline = Pipeline::new(even_squares.capacity(1).combine(0, |a, b| a + b))
+            .then(double.capacity(1).combine(0, |a, b| a + b));
+
+        // the sink is slow, so the stages have to wait for it
+        let mut outputs = Vec::new();
+        let counts = pipeline.run(0..100, |x| {
+            std::thread::sleep(Duration::from_millis(1));
+            outputs.push(x);
+            let ahead = emitted.load(Ordering::SeqCst) - outputs.len();
+            assert!(ahead <= 16, "{ahead} items ahead of the sink");
+        });
+        assert_eq!(counts, (100, 50));
+        outputs.sort();
+        let expected: Vec<_> = (0..100).step_by(2).map(|x| 2 * x * x).collect();
+        assert_eq!(outputs, expected);
+    }
+}
@@ -1,28 +1,154 @@
 //! Thread pool.
 
-use crate::{channel, sender, Options, Sender};
+use crate::{
+    adaptive, handle,
+    queue::Shared,
+    sender::SenderFunction,
+    worker::{Spawn, Workers},
+    CancelToken, Handler, JobHandle, JoinError, Options, Panic, Sender, Stats, WorkerInfo,
+};
+use std::sync::Arc;
+use std::thread::JoinHandle;
 
 /// The thread pool that executes the jobs.
 ///
-/// The generic type W specificies the worker-state.
-pub struct Pool<W, X> {
+/// The generic type W specificies the worker-state.  The jobs are closures
+/// unless the pool was created by `with_handler` with jobs of type J.
+pub struct Pool<W, X, J = SenderFunction<'static, W>> {
     /// References to the worker threads.
-    worker: Vec<std::thread::JoinHandle<X>>,
-    /// The sender for putting in the jobs.
-    sender: Sender<W>,
+    workers: Arc<Workers<'static, 'static, W, JoinHandle<X>, J>>,
+    /// The thread adapting the number of workers.
+    monitor: Option<JoinHandle<()>>,
+    /// The thread reporting the progress.
+    reporter: Option<JoinHandle<()>>,
+    /// The sender for putting in the jobs until the pool joins.
+    sender: Option<Sender<'static, W, J>>,
 }
 
-impl<W, X> Pool<W, X> {
+impl<W, X, J> Pool<W, X, J> {
     /// Join all threads.
+    ///
+    /// Panics if a job panicked.  See `try_join` for handling the panics.
     pub fn join(self) -> Vec<X> {
+        self.try_join().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Join all threads and return the statistics as well.
+    ///
+    /// Panics if a job panicked.  See `Options::stats`.
+    pub fn join_with_stats(self) -> (Vec<X>, Stats) {
+        let shared = self.workers.shared.clone();
+        let states = self.join();
+        (states, shared.stats())
+    }
+
+    /// Join all threads and return the panics of the jobs.
+    ///
+    /// The partial result of the error holds the states of the workers that survived.
+    /// The states of retired workers are included.
+    pub fn try_join(mut self) -> Result<Vec<X>, JoinError<Vec<X>>> {
+        let shared = self.workers.shared.clone();
+        let mut states = Vec::new();
+        let mut panics = Vec::new();
+        if let Err(payload) = shared.close_timers() {
+            panics.push(Panic { worker: 0, payload });
+        }
+        shared.progress.close();
+        if let Some(Err(payload)) = self.reporter.take().map(|x| x.join()) {
+            panics.push(Panic { worker: 0, payload });
+        }
+
         // drop the sender so that we finish if nobody is live anymore
-        drop(self.sender);
-        self.worker.into_iter().map(|w| w.join().unwrap()).collect()
+        drop(self.sender.take());
+        if let Some(monitor) = self.monitor.take() {
+            monitor.thread().unpark();
+            if let Err(payload) = monitor.join() {
+                panics.push(Panic { worker: 0, payload });
+            }
+        }
+        for (worker, w) in self.workers.take_handles() {
+            match w.join() {
+                Ok(x) => states.push(x),
+                Err(payload) => panics.push(Panic { worker, payload }),
+            }
+        }
+        JoinError::check(&shared, panics, states)
+    }
+
+    /// Start another worker.  Returns false if `max_threads` workers are running.
+    pub fn add_worker(&self) -> bool {
+        self.workers.add()
+    }
+
+    /// Let a worker retire once it is idle.  Returns false if only one would be left.
+    ///
+    /// The state of a retired worker is destroyed and returned by `join`.
+    pub fn retire_worker(&self) -> bool {
+        self.workers.shared.retire()
+    }
+
+    /// Return the number of running workers.
+    pub fn workers(&self) -> usize {
+        self.workers.shared.workers()
+    }
+
+    /// Wait until all jobs sent so far and the jobs they sent have finished.
+    ///
+    /// The workers keep running with their states.  Jobs sent while waiting
+    /// are waited for as well.
+    pub fn wait_idle(&self) {
+        self.workers.shared.wait_idle()
     }
 
     /// Return a reference to the sender so that the queue can be filled.
-    pub fn sender(&self) -> &Sender<W> {
-        &self.sender
+    pub fn sender(&self) -> &Sender<'static, W, J> {
+        self.sender.as_ref().expect("the pool is not joined yet")
+    }
+
+    /// Return a handle to cancel the jobs.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.sender().cancel_token()
+    }
+}
+
+impl<W, X> Pool<W, X> {
+    /// Run the closure on the state of every running worker and return the results.
+    ///
+    /// The workers run it after their current job.  This can snapshot the
+    /// states between phases or reset them, for example with `std::mem::take`.
+    /// Workers that die or are cancelled before running it are skipped.
+    pub fn broadcast<T, F>(&self, f: F) -> Vec<T>
+    where
+        T: Send + 'static,
+        F: Fn(&mut W) -> T + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        let mut handles = Vec::new();
+        self.workers.shared.post(|| {
+            let (promise, handle) = handle::new();
+            handles.push(handle);
+            let f = f.clone();
+            Box::new(move |state| promise.set(f(state)))
+        });
+        handles.into_iter().filter_map(|x| x.wait()).collect()
+    }
+
+    /// Send a job from outside of the workers and return a handle to its value.
+    ///
+    /// The job is always queued.  The handle can be awaited.  A pool without
+    /// workers drops the job, so that the handle returns None, as there is no
+    /// state to run it on.
+    pub fn spawn<T, F>(&self, job: F) -> JobHandle<T>
+    where
+        F: FnOnce(&mut W) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (promise, handle) = handle::new();
+        if !self.sender().is_cancelled() && self.workers() > 0 {
+            let job = move |state: &mut W| promise.set(job(state));
+            self.workers.shared.push(None, Box::new(job));
+        }
+        handle
     }
 }
 
@@ -37,37 +163,203 @@ impl<W: 'static, X: Send + 'static> Pool<W, X> {
         create: fn(T) -> W,
         destroy: fn(W) -> X,
     ) -> Self {
-        let threads = options.get_threads();
+        Pool::with_handler(options, param, create, destroy, |_, state, job| job(state))
+    }
+}
 
-        // the bounded Job queue
-        let (sender, receiver) =
-            channel::bounded::<sender::SenderFunction<W>>(threads * options.slots);
+impl<W: 'static, X: Send + 'static, J: Send + 'static> Pool<W, X, J> {
+    /// Create a new pool that sends plain values to a single handler.
+    ///
+    /// The values are queued as they are, without boxing them into closures,
+    /// and can be inspected with `Sender::inspect`.  They are sent with
+    /// `Sender::send_value` and handled synchronously if the queue is full.
+    /// Everything else works like with `new`.
+    pub fn with_handler<T: Send + Clone + 'static>(
+        options: Options,
+        param: T,
+        create: fn(T) -> W,
+        destroy: fn(W) -> X,
+        handler: Handler<'static, W, J>,
+    ) -> Self {
+        let infos = options.get_workers().expect("placing the workers");
+
+        // the work-stealing job queues
+        let shared = Shared::with_handler(infos.len(), &options, handler);
 
         // creating all worker threads with their own state
-        let worker = (0..threads)
-            .map(|_| {
-                let receiver = receiver.clone();
+        let spawn: Spawn<'static, J, _> = Box::new({
+            let shared = shared.clone();
+            let options = options.clone();
+            move |info: WorkerInfo, local| {
+                let shared = shared.clone();
                 let param = param.clone();
-                std::thread::spawn(move || {
-                    let mut state = create(param);
-                    for job in receiver {
-                        job(&mut state);
-                    }
-                    destroy(state)
-                })
-            })
-            .collect();
+                options
+                    .thread(info.index)
+                    .spawn(move || {
+                        let state = shared.run(info.index, local, || {
+                            info.setup();
+                            create(param)
+                        });
+                        destroy(state)
+                    })
+                    .expect("spawning a worker")
+            }
+        });
+        let threads = options.get_threads();
+        let workers = Arc::new(Workers::new(shared.clone(), infos, threads, spawn));
+        let monitor = options.adaptive.then(|| {
+            let workers = workers.clone();
+            options
+                .thread("monitor")
+                .spawn(move || adaptive::monitor(&workers, threads))
+                .expect("spawning the monitor")
+        });
+
+        let reporter = shared.progress.is_enabled().then(|| {
+            let shared = shared.clone();
+            options
+                .thread("progress")
+                .spawn(move || shared.progress.run())
+                .expect("spawning the progress reporter")
+        });
 
         Self {
-            worker,
-            sender: Sender(sender),
+            workers,
+            monitor,
+            reporter,
+            sender: Some(Sender::new(shared)),
         }
     }
 }
 
+impl<W, X, J> Drop for Pool<W, X, J> {
+    /// Stop the timer thread and the progress reporter if the pool was not joined.
+    ///
+    /// The workers still run the queued jobs in the background.
+    fn drop(&mut self) {
+        let _ = self.workers.shared.timers.close();
+        self.workers.shared.progress.close();
+    }
+}
+
 /// Convinience function.
 impl<W: Default + Send + 'static> Default for Pool<W, W> {
     fn default() -> Self {
         crate::Pool::new(Options::default(), (), |_| Default::default(), |x| x)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{Options, Pool, Progress};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn phases() {
+        let pool = Pool::new(Options::default().threads(Some(2)), (), |_| 0, |x: usize| x);
+        for _ in 0..4 {
+            let sender = pool.sender().clone();
+            pool.sender().send(&mut 0, move |state| {
+                *state += 1;
+                for _ in 0..2 {
+                    sender.send(state, |state| {
+                        std::thread::sleep(Duration::from_millis(10));
+                        *state += 1;
+                    });
+                }
+            });
+        }
+        pool.wait_idle();
+        let counts = pool.broadcast(std::mem::take);
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts.iter().sum::<usize>(), 12);
+        assert_eq!(pool.broadcast(|x| *x), [0, 0]);
+
+        pool.sender().send(&mut 0, |state| *state += 1);
+        assert_eq!(pool.join().iter().sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn spawn_without_workers() {
+        let options = Options::default().threads(Some(1)).one_is_zero();
+        let pool = Pool::new(options, (), |_| 0, |x: i32| x);
+        assert_eq!(pool.workers(), 0);
+        assert_eq!(pool.spawn(|_| 42).wait(), None);
+        assert_eq!(pool.sender().spawn_async(|_| async { 42 }).wait(), None);
+        pool.join();
+    }
+
+    #[test]
+    fn with_handler() {
+        let options = Options::default().threads(Some(2)).slots(1);
+        let pool = Pool::with_handler(
+            options,
+            (),
+            |_| 0,
+            |x: u64| x,
+            |sender, sum, value: u64| {
+                *sum += value;
+                if value > 1 {
+                    sender.send_value(sum, value / 2);
+                }
+            },
+        );
+        let sender = pool.sender();
+        sender.inspect(|_| unreachable!());
+        for value in [64, 32] {
+            sender.send_value(&mut 0, value);
+        }
+        pool.wait_idle();
+        assert_eq!(sender.queued(), 0);
+        assert_eq!(pool.join().iter().sum::<u64>(), 127 + 63);
+    }
+
+    #[test]
+    fn inspect() {
+        let pool = Pool::with_handler(
+            Options::default().threads(Some(1)),
+            (),
+            |_| (),
+            |_| (),
+            |sender, _, value: &'static str| {
+                if value == "first" {
+                    sender.send_value(&mut (), "second");
+                    sender.send_value(&mut (), "third");
+                    let queued = format!("{sender:?}");
+                    assert_eq!(queued, r#"Sender { queued: ["second", "third"], .. }"#);
+                }
+            },
+        );
+        pool.sender().send_value(&mut (), "first");
+        pool.wait_idle();
+        pool.join();
+    }
+
+    #[test]
+    fn drop_stops_helpers() {
+        let last = Arc::new(AtomicBool::new(false));
+        let progress = Progress::new(&["jobs"]).interval(Duration::from_millis(1));
+        let progress = progress.callback({
+            let last = last.clone();
+            move |report| last.store(report.last, Ordering::SeqCst)
+        });
+        let pool = Pool::new(Options::default().progress(progress), (), |_| (), |_| ());
+        let runs = Arc::new(AtomicUsize::new(0));
+        pool.sender().send_every(Duration::from_millis(1), {
+            let runs = runs.clone();
+            move |_| {
+                runs.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        drop(pool);
+
+        // the workers still finish the jobs queued before
+        std::thread::sleep(Duration::from_millis(20));
+        let before = runs.load(Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(runs.load(Ordering::SeqCst), before);
+        assert!(last.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,151 @@
+//! A queue ordered by priority.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Mutex;
+
+/// An entry in the heap.
+struct Entry<T> {
+    priority: isize,
+    /// Keeps entries with the same priority in FIFO order.
+    sequence: Reverse<usize>,
+    value: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.priority, self.sequence).cmp(&(other.priority, other.sequence))
+    }
+}
+
+/// A locked heap that returns the value with the highest priority first.
+pub(crate) struct PriorityQueue<T> {
+    /// The next sequence number and the heap.
+    heap: Mutex<(usize, BinaryHeap<Entry<T>>)>,
+    /// The length to check the queue without locking.
+    len: AtomicUsize,
+}
+
+impl<T> PriorityQueue<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            heap: Mutex::new((0, BinaryHeap::new())),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return the number of values.
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Return true if there are no values.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Push a value with the given priority.
+    pub(crate) fn push(&self, priority: isize, value: T) {
+        let mut heap = self.heap.lock().unwrap();
+        let sequence = Reverse(heap.0);
+        heap.0 += 1;
+        heap.1.push(Entry {
+            priority,
+            sequence,
+            value,
+        });
+        self.len.store(heap.1.len(), atomic::Ordering::SeqCst);
+    }
+
+    /// Call the function on every value in no particular order.
+    pub(crate) fn for_each(&self, f: impl FnMut(&T)) {
+        let heap = self.heap.lock().unwrap();
+        heap.1.iter().map(|x| &x.value).for_each(f);
+    }
+
+    /// Pop the value with the highest priority.
+    pub(crate) fn pop(&self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut heap = self.heap.lock().unwrap();
+        let entry = heap.1.pop();
+        self.len.store(heap.1.len(), atomic::Ordering::SeqCst);
+        entry.map(|x| x.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{execute, Options, Order, Sender};
+
+    /// Send the jobs from a job on the only worker and return the order they ran in.
+    fn run(options: Options, send: fn(&Sender<'_, Vec<usize>>, &mut Vec<usize>)) -> Vec<usize> {
+        execute(
+            options.threads(Some(1)),
+            |_| Vec::new(),
+            |x| x,
+            |s| {
+                let sender = s.clone();
+                s.send(&mut Vec::new(), move |state| send(&sender, state));
+                Vec::new()
+            },
+            |mut a, b| {
+                a.extend(b);
+                a
+            },
+        )
+    }
+
+    /// Return a job recording its number.
+    fn push(i: usize) -> impl FnOnce(&mut Vec<usize>) {
+        move |x| x.push(i)
+    }
+
+    #[test]
+    fn priority_first() {
+        let ran = run(Options::default(), |s, state| {
+            s.send(state, push(0));
+            s.send(state, push(1));
+            s.send_with_priority(state, 5, push(2));
+            s.send_with_priority(state, 7, push(3));
+            s.send_with_priority(state, 5, push(4));
+        });
+        assert_eq!(ran, [3, 2, 4, 0, 1]);
+
+        let ran = run(Options::default().order(Order::Priority), |s, state| {
+            s.send(state, push(0));
+            s.send_with_priority(state, -1, push(1));
+            s.send(state, push(2));
+            s.send_with_priority(state, 1, push(3));
+        });
+        assert_eq!(ran, [3, 0, 2, 1]);
+    }
+
+    #[test]
+    fn local_order() {
+        let send: fn(&Sender<'_, Vec<usize>>, &mut Vec<usize>) = |s, state| {
+            (0..4).for_each(|i| s.send(state, push(i)));
+        };
+        assert_eq!(run(Options::default(), send), [0, 1, 2, 3]);
+        assert_eq!(
+            run(Options::default().order(Order::Lifo), send),
+            [3, 2, 1, 0]
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Progress counters defined by the user and their reporter thread.
+//!
+//! Every worker adds to its own set of counters.  The reporter thread sums
+//! them up periodically and renders them to stderr or hands them to a callback.
+
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// The function receiving the reports.
+type Callback = Arc<dyn Fn(&ProgressReport) + Send + Sync>;
+
+/// The progress counters of a pool and how they are reported.
+///
+/// The counters are addressed by their index in `Sender::progress`.
+#[derive(Clone)]
+pub struct Progress {
+    names: Vec<&'static str>,
+    interval: Duration,
+    callback: Option<Callback>,
+}
+
+impl Progress {
+    /// Define the counters by their names.
+    ///
+    /// They are rendered to stderr every second by default.
+    pub fn new(names: &[&'static str]) -> Self {
+        Self {
+            names: names.to_vec(),
+            interval: Duration::from_secs(1),
+            callback: None,
+        }
+    }
+
+    /// Set the time between two reports.
+    pub fn interval(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    /// Hand the reports to the callback instead of rendering them to stderr.
+    pub fn callback(self, callback: impl Fn(&ProgressReport) + Send + Sync + 'static) -> Self {
+        Self {
+            callback: Some(Arc::new(callback)),
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Debug for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Progress")
+            .field("names", &self.names)
+            .field("interval", &self.interval)
+            .field("callback", &self.callback.is_some())
+            .finish()
+    }
+}
+
+/// A counter in a report.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgressCounter {
+    /// The name given to `Progress::new`.
+    pub name: &'static str,
+    /// The sum over all workers.
+    pub total: u64,
+    /// The increase per second since the previous report.
+    ///
+    /// This is the average over the whole run in the last report.
+    pub rate: f64,
+}
+
+/// The state of the progress counters at a point in time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgressReport {
+    /// The time since the pool was started.
+    pub elapsed: Duration,
+    /// The counters in the order of their definition.
+    pub counters: Vec<ProgressCounter>,
+    /// Is this the last report when the pool joins?
+    pub last: bool,
+}
+
+impl std::fmt::Display for ProgressReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.1}s", self.elapsed.as_secs_f64())?;
+        for x in &self.counters {
+            write!(f, " {} {} ({:.0}/s)", x.name, x.total, x.rate)?;
+        }
+        Ok(())
+    }
+}
+
+/// The progress counters of the workers.
+pub(crate) struct Meter {
+    progress: Option<Progress>,
+    /// The counters per worker.  Index zero is used by threads outside of the pool.
+    counters: Vec<Box<[AtomicU64]>>,
+    /// Is the meter closed?
+    closed: Mutex<bool>,
+    /// Wakes up the reporter.
+    wakeup: Condvar,
+}
+
+impl Meter {
+    pub(crate) fn new(progress: Option<Progress>, threads: usize) -> Self {
+        let len = progress.as_ref().map_or(0, |x| x.names.len());
+        let counters = match progress {
+            Some(_) => (0..=threads)
+                .map(|_| (0..len).map(|_| AtomicU64::new(0)).collect())
+                .collect(),
+            None => Vec::new(),
+        };
+        Self {
+            progress,
+            counters,
+            closed: Mutex::new(false),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    /// Is a reporter needed?
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.progress.is_some()
+    }
+
+    /// Add to a counter of the worker.
+    ///
+    /// Does nothing without progress and panics if the counter was not defined.
+    pub(crate) fn add(&self, worker: usize, counter: usize, amount: u64) {
+        if let Some(counters) = self.counters.get(worker) {
+            counters[counter].fetch_add(amount, Ordering::Relaxed);
+        }
+    }
+
+    /// Sum up the counters of all workers.
+    fn totals(&self) -> Vec<u64> {
+        let len = self.counters.first().map_or(0, |x| x.len());
+        (0..len)
+            .map(|i| {
+                self.counters
+                    .iter()
+                    .map(|x| x.get(i).map_or(0, |x| x.load(Ordering::Relaxed)))
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Report the counters periodically until the meter is closed.
+    ///
+    /// The last report is made after closing.
+    pub(crate) fn run(&self) {
+        let Some(progress) = &self.progress else {
+            return;
+        };
+        let terminal = progress.callback.is_none() && std::io::stderr().is_terminal();
+        let start = Instant::now();
+        let mut previous = (start, self.totals());
+        let mut closed = self.closed.lock().unwrap();
+        loop {
+            let due = previous.0 + progress.interval;
+            let now = Instant::now();
+            if !*closed && due > now {
+                closed = self.wakeup.wait_timeout(closed, due - now).unwrap().0;
+                continue;
+            }
+
+            // the rates since the previous report or the start
+            let last = *closed;
+            drop(closed);
+            let totals = self.totals();
+            let since = if last {
+                (start, vec![0; totals.len()])
+            } else {
+                previous
+            };
+            let seconds = now.duration_since(since.0).as_secs_f64().max(1e-9);
+            let report = ProgressReport {
+                elapsed: now.duration_since(start),
+                counters: progress
+                    .names
+                    .iter()
+                    .zip(totals.iter().zip(since.1))
+                    .map(|(&name, (&total, old))| ProgressCounter {
+                        name,
+                        total,
+                        rate: total.saturating_sub(old) as f64 / seconds,
+                    })
+                    .collect(),
+                last,
+            };
+            match &progress.callback {
+                Some(callback) => callback(&report),
+                None if terminal => {
+                    let end = if last { "\n" } else { "" };
+                    let _ = write!(std::io::stderr(), "\r\x1b[K{report}{end}");
+                }
+                None => {
+                    let _ = writeln!(std::io::stderr(), "{report}");
+                }
+            }
+            if last {
+                return;
+            }
+            previous = (now, totals);
+            closed = self.closed.lock().unwrap();
+        }
+    }
+
+    /// Stop the reporter after its last report.
+    pub(crate) fn close(&self) {
+        *self.closed.lock().unwrap() = true;
+        self.wakeup.notify_all();
+    }
+}
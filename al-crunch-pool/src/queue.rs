@@ -0,0 +1,669 @@
+//! The work-stealing job queues.
+//!
+//! Every worker owns a local deque.  Jobs submitted from a worker go to its
+//! local deque, jobs submitted from the outside go to a shared injector.  Idle
+//! workers steal from the injector first and from the other workers second.
+//! Jobs with a priority are kept in a separate queue that is checked before.
+//!
+//! The local queues are allocated for the maximum number of workers.  A
+//! worker takes a free one when it starts and returns it when it retires.
+
+use crate::{
+    group::Enter,
+    priority::PriorityQueue,
+    progress::Meter,
+    sender::{Handler, Sender, SenderFunction},
+    stats::Counters,
+    timer::{TimerJob, Timers},
+    CancelToken, Options, Order, Panic, SignalGuard, Stats,
+};
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+use std::cell::Cell;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{fence, AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+thread_local! {
+    /// The shared state, the local queue and the index of the worker running on this thread.
+    static CURRENT: Cell<(*const (), *const (), usize)> = const { Cell::new((std::ptr::null(), std::ptr::null(), 0)) };
+}
+
+/// The local queue of a worker.
+pub(crate) type Local<J> = Worker<J>;
+
+/// The jobs sent to a single worker or None if no worker runs on the slot.
+type Mailbox<J> = Option<Vec<J>>;
+
+/// What a worker is doing.
+#[derive(Default)]
+pub(crate) struct Activity {
+    /// The thread id of the worker or zero if the slot is free.
+    pub(crate) tid: AtomicI32,
+    /// Is the worker running a job?
+    pub(crate) busy: AtomicBool,
+    /// The statistics of the worker.
+    counters: Counters,
+}
+
+/// The state shared between the workers and the senders of a pool.
+///
+/// The generic type J is the type of the queued jobs.  They are boxed
+/// closures unless the pool was created with a handler for plain values.
+pub(crate) struct Shared<'a, W, J = SenderFunction<'a, W>> {
+    /// Runs a job on the state of a worker.
+    pub(crate) handler: Handler<'a, W, J>,
+    /// Jobs submitted from outside of the workers.
+    injector: Injector<J>,
+    /// Steal jobs from the local queues of the workers.
+    stealers: Vec<Stealer<J>>,
+    /// The local queues without a worker.
+    free: Mutex<Vec<(usize, Local<J>)>>,
+    /// The jobs sent to the individual workers.
+    mail: Mutex<Vec<Mailbox<J>>>,
+    /// What the workers are doing.
+    pub(crate) activity: Vec<Activity>,
+    /// The number of running workers.
+    active: AtomicUsize,
+    /// The number of workers that should retire.
+    retiring: AtomicUsize,
+    /// Jobs with a priority.
+    prioritized: PriorityQueue<J>,
+    /// The order of the jobs.
+    order: Order,
+    /// The capacity of a local queue.
+    slots: usize,
+    /// The number of live senders.  The workers finish when it drops to zero.
+    ///
+    /// Starts with one for the sender that is handed out with the pool.
+    pub(crate) senders: AtomicUsize,
+    /// The number of workers waiting for jobs.
+    sleeping: AtomicUsize,
+    /// The number of jobs that were queued but have not finished yet.
+    pending: AtomicUsize,
+    /// The lock the idle workers wait on.
+    lock: Mutex<()>,
+    /// Wakes up the idle workers.
+    wakeup: Condvar,
+    /// Wakes up the threads waiting for the pending jobs.
+    idle: Condvar,
+    /// Catch the panics of the jobs instead of unwinding the worker.
+    catch_panics: bool,
+    /// The panics caught so far.
+    panics: Mutex<Vec<Panic>>,
+    /// Collect statistics.
+    stats: bool,
+    /// The jobs executed synchronously outside of the workers.
+    inline: AtomicU64,
+    /// Drop the jobs instead of running them when cancelled.
+    pub(crate) cancel: CancelToken,
+    /// The delayed and periodic jobs.
+    pub(crate) timers: Timers<'a, J>,
+    /// The progress counters of the workers.
+    pub(crate) progress: Meter,
+    /// Keeps the signal handlers installed while the pool lives.
+    signals: Option<SignalGuard>,
+}
+
+impl<'a, W> Shared<'a, W> {
+    /// Create the shared state for closures with local queues for the maximum number of workers.
+    pub(crate) fn new(threads: usize, options: &Options) -> Arc<Self> {
+        Self::with_handler(threads, options, |_, state, job| job(state))
+    }
+}
+
+impl<'a, W, J> Shared<'a, W, J> {
+    /// Create the shared state that calls the handler with every job.
+    pub(crate) fn with_handler(
+        threads: usize,
+        options: &Options,
+        handler: Handler<'a, W, J>,
+    ) -> Arc<Self> {
+        let locals: Vec<_> = (0..threads)
+            .map(|_| match options.order {
+                Order::Lifo => Worker::new_lifo(),
+                _ => Worker::new_fifo(),
+            })
+            .collect();
+        let mut shared = Self {
+            handler,
+            injector: Injector::new(),
+            stealers: locals.iter().map(|x| x.stealer()).collect(),
+            free: Mutex::new(locals.into_iter().enumerate().rev().collect()),
+            mail: Mutex::new((0..threads).map(|_| None).collect()),
+            activity: (0..threads).map(|_| Default::default()).collect(),
+            active: AtomicUsize::new(0),
+            retiring: AtomicUsize::new(0),
+            prioritized: PriorityQueue::new(),
+            order: options.order,
+            slots: options.slots,
+            senders: AtomicUsize::new(1),
+            sleeping: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            lock: Mutex::new(()),
+            wakeup: Condvar::new(),
+            idle: Condvar::new(),
+            catch_panics: options.catch_panics,
+            panics: Mutex::new(Vec::new()),
+            stats: options.stats,
+            inline: AtomicU64::new(0),
+            cancel: CancelToken::default(),
+            timers: Timers::new(options.thread("timer")),
+            progress: Meter::new(options.progress.clone(), threads),
+            signals: None,
+        };
+        if options.cancel_on_signals {
+            let signals = [libc::SIGINT, libc::SIGTERM];
+            let guard = shared.cancel.cancel_on_signals(&signals);
+            shared.signals = Some(guard.expect("installing the signal handlers"));
+        }
+        if threads == 0 {
+            // there is no timer thread
+            let _ = shared.timers.close();
+        }
+        Arc::new(shared)
+    }
+
+    /// Take a free local queue for a new worker.
+    pub(crate) fn take_local(&self) -> Option<(usize, Local<J>)> {
+        let res = self.free.lock().unwrap().pop();
+        if let Some((slot, _)) = &res {
+            self.mail.lock().unwrap()[*slot] = Some(Vec::new());
+            self.active.fetch_add(1, Ordering::SeqCst);
+        }
+        res
+    }
+
+    /// Ask one of the workers to retire.  Returns false if only one would be left.
+    pub(crate) fn retire(&self) -> bool {
+        let res = self
+            .retiring
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                (x + 1 < self.active.load(Ordering::SeqCst)).then_some(x + 1)
+            })
+            .is_ok();
+        if res {
+            self.shutdown();
+        }
+        res
+    }
+
+    /// Should the current worker retire?
+    fn claim_retire(&self) -> bool {
+        self.retiring.load(Ordering::Relaxed) > 0
+            && self
+                .retiring
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| x.checked_sub(1))
+                .is_ok()
+    }
+
+    /// Return the index and the local queue if we run on one of our workers.
+    fn current(&self) -> Option<(usize, &Local<J>)> {
+        let (shared, local, index) = CURRENT.get();
+        if shared != self as *const _ as *const () {
+            return None;
+        }
+        // SAFETY: the pointer was set by `run` on this thread and stays valid until it returns
+        Some((index, unsafe { &*(local as *const Local<J>) }))
+    }
+
+    /// Return the local queue if we run on one of our workers.
+    fn local(&self) -> Option<&Local<J>> {
+        self.current().map(|x| x.1)
+    }
+
+    /// Return the counters of the current worker if statistics are collected.
+    fn counters(&self) -> Option<&Counters> {
+        let (index, _) = self.current().filter(|_| self.stats)?;
+        Some(&self.activity[index - 1].counters)
+    }
+
+    /// Count a job that is executed synchronously.
+    pub(crate) fn count_inline(&self) {
+        match self.counters() {
+            Some(counters) => counters.inline(),
+            None if self.stats => {
+                self.inline.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {}
+        }
+    }
+
+    /// Add to a progress counter of the current worker.
+    pub(crate) fn add_progress(&self, counter: usize, amount: u64) {
+        let worker = self.current().map_or(0, |x| x.0);
+        self.progress.add(worker, counter, amount);
+    }
+
+    /// Return the statistics collected so far.
+    pub(crate) fn stats(&self) -> Stats {
+        Stats {
+            workers: self.activity.iter().map(|x| x.counters.get()).collect(),
+            inline: self.inline.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Return the number of running workers.
+    pub(crate) fn workers(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Return the priority a job is queued with.
+    fn priority(&self, priority: Option<isize>) -> Option<isize> {
+        match self.order {
+            Order::Priority => priority.or(Some(0)),
+            _ => priority,
+        }
+    }
+
+    /// Is the queue we would push to full?
+    ///
+    /// The local queue holds `slots` jobs, the shared queues `slots` per worker.
+    pub(crate) fn is_full(&self, priority: Option<isize>) -> bool {
+        if self.priority(priority).is_some() {
+            return self.prioritized.len() >= self.workers() * self.slots;
+        }
+        match self.local() {
+            Some(local) => local.len() >= self.slots,
+            None => self.injector.len() >= self.workers() * self.slots,
+        }
+    }
+
+    /// Push a job to the priority queue, the local queue or to the injector.
+    pub(crate) fn push(&self, priority: Option<isize>, job: J) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        match (self.priority(priority), self.local()) {
+            (Some(priority), _) => self.prioritized.push(priority, job),
+            (None, Some(local)) => {
+                local.push(job);
+                if let Some(counters) = self.counters() {
+                    counters.queue(local.len());
+                }
+            }
+            (None, None) => self.injector.push(job),
+        }
+        self.notify();
+    }
+
+    /// Send a job to every worker.
+    ///
+    /// Workers that start later do not get the job.
+    pub(crate) fn post(&self, mut job: impl FnMut() -> J) {
+        for mailbox in self.mail.lock().unwrap().iter_mut().flatten() {
+            self.pending.fetch_add(1, Ordering::SeqCst);
+            mailbox.push(job());
+        }
+        self.shutdown();
+    }
+
+    /// Take the next job sent to the worker.
+    fn take_mail(&self, index: usize) -> Option<J> {
+        let mut mail = self.mail.lock().unwrap();
+        mail[index - 1].as_mut().and_then(|x| x.pop())
+    }
+
+    /// Mark a job as finished.
+    fn done(&self) {
+        if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _guard = self.lock.lock().unwrap();
+            self.idle.notify_all();
+        }
+    }
+
+    /// Wait until all queued jobs and the jobs they sent have finished.
+    ///
+    /// Returns early if no worker is left to run them.
+    pub(crate) fn wait_idle(&self) {
+        let mut guard = self.lock.lock().unwrap();
+        while self.pending.load(Ordering::SeqCst) > 0 && self.workers() > 0 {
+            guard = self.idle.wait(guard).unwrap();
+        }
+    }
+
+    /// Queue the jobs of the timers when they are due until they are closed.
+    pub(crate) fn run_timers(&self) {
+        self.timers.run(|job| {
+            if !self.cancel.is_cancelled() {
+                self.push(None, job);
+            }
+        });
+    }
+
+    /// Add a timer and start the timer thread if it is the first one.
+    pub(crate) fn add_timer(self: &Arc<Self>, due: Instant, job: TimerJob<'a, J>) -> CancelToken
+    where
+        J: Send,
+    {
+        let shared = self.clone();
+        self.timers.add(due, job, |builder| {
+            // SAFETY: the thread is joined by `Timers::close` before the scope
+            // of the jobs ends, see `close_timers` and `Helpers`
+            unsafe { builder.spawn_unchecked(move || shared.run_timers()) }
+        })
+    }
+
+    /// Cancel the timers once all queued jobs have finished.
+    ///
+    /// Returns the panic of the timer thread.
+    pub(crate) fn close_timers(&self) -> std::thread::Result<()> {
+        self.wait_idle();
+        self.timers.close()
+    }
+
+    /// Wake up an idle worker.
+    fn notify(&self) {
+        fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::Relaxed) > 0 {
+            let _guard = self.lock.lock().unwrap();
+            self.wakeup.notify_one();
+        }
+    }
+
+    /// Wake up all workers as the last sender is gone or one should retire.
+    pub(crate) fn shutdown(&self) {
+        let _guard = self.lock.lock().unwrap();
+        self.wakeup.notify_all();
+    }
+
+    /// Are all queues empty?
+    pub(crate) fn is_empty(&self) -> bool {
+        self.prioritized.is_empty()
+            && self.injector.is_empty()
+            && self.stealers.iter().all(|x| x.is_empty())
+    }
+
+    /// Take a job with a priority, from the local queue or steal one.
+    fn next(&self, local: &Local<J>) -> Option<J> {
+        self.prioritized
+            .pop()
+            .or_else(|| local.pop())
+            .or_else(|| self.steal(Some(local)))
+    }
+
+    /// Steal a job from the injector or from the other workers.
+    ///
+    /// A batch of jobs is moved to the local queue if we have one.
+    fn steal(&self, local: Option<&Local<J>>) -> Option<J> {
+        loop {
+            let steal = match local {
+                Some(local) => self.injector.steal_batch_and_pop(local),
+                None => self.injector.steal(),
+            }
+            .or_else(|| {
+                self.stealers
+                    .iter()
+                    .map(|x| x.steal())
+                    .collect::<Steal<_>>()
+            });
+            if !steal.is_retry() {
+                break steal.success();
+            }
+        }
+    }
+
+    /// Run one of the queued jobs on the state.  Returns false if there was none.
+    ///
+    /// This lets a waiting job help instead of blocking its worker.  The
+    /// sender is given to the handler.
+    pub(crate) fn help(&self, sender: &Sender<'a, W, J>, state: &mut W) -> bool {
+        let job = match self.local() {
+            Some(local) => self.next(local),
+            None => self.prioritized.pop().or_else(|| self.steal(None)),
+        };
+        let Some(job) = job else {
+            return false;
+        };
+        let _done = Done(self);
+        let _group = Enter::new(None);
+        if let Some(counters) = self.counters() {
+            counters.job();
+        }
+        if !self.cancel.is_cancelled() {
+            (self.handler)(sender, state, job);
+        }
+        true
+    }
+
+    /// Run the next job as a virtual worker of a simulation.  Returns false if there was none.
+    ///
+    /// The worker steals from the others beginning with the victim.
+    pub(crate) fn step(
+        self: &Arc<Self>,
+        index: usize,
+        local: &Local<J>,
+        state: &mut W,
+        victim: usize,
+    ) -> bool {
+        let _current = Current::enter(self, local, index);
+        let n = self.stealers.len();
+        let job = self
+            .prioritized
+            .pop()
+            .or_else(|| local.pop())
+            .or_else(|| self.injector.steal_batch_and_pop(local).success())
+            .or_else(|| (0..n).find_map(|i| self.stealers[(victim + i) % n].steal().success()));
+        let Some(job) = job else {
+            return false;
+        };
+        if let Some(counters) = self.counters() {
+            counters.job();
+        }
+        self.execute(&Sender::uncounted(self.clone()), index, job, state);
+        true
+    }
+
+    /// Wait for the next job.
+    ///
+    /// Returns None when all senders are gone and the queues are empty or if
+    /// the worker should retire.
+    fn wait(&self, index: usize, local: &Local<J>) -> Option<J> {
+        loop {
+            if let Some(job) = self.take_mail(index) {
+                return Some(job);
+            }
+            // keep the local jobs so that nobody has to steal them
+            if local.is_empty() && self.claim_retire() {
+                return None;
+            }
+            if let Some(job) = self.next(local) {
+                return Some(job);
+            }
+            let mut guard = self.lock.lock().unwrap();
+            self.sleeping.fetch_add(1, Ordering::SeqCst);
+            fence(Ordering::SeqCst);
+            while self.is_empty()
+                && self.retiring.load(Ordering::SeqCst) == 0
+                && self.mail.lock().unwrap()[index - 1]
+                    .as_ref()
+                    .is_none_or(|x| x.is_empty())
+            {
+                if self.senders.load(Ordering::SeqCst) == 0 {
+                    self.sleeping.fetch_sub(1, Ordering::SeqCst);
+                    return None;
+                }
+                guard = self.wakeup.wait(guard).unwrap();
+            }
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Run the jobs on the worker until all senders are gone or it retires.
+    ///
+    /// The state is created first, so that the worker leaves the pool even if
+    /// setting it up panics.  The local queue is returned afterwards.  Its
+    /// remaining jobs can still be stolen.  Returns the state.
+    pub(crate) fn run(
+        self: &Arc<Self>,
+        index: usize,
+        local: Local<J>,
+        create: impl FnOnce() -> W,
+    ) -> W {
+        let activity = &self.activity[index - 1];
+        let _leave = Leave(self, index);
+        let mut state = create();
+        let sender = Sender::uncounted(self.clone());
+        activity
+            .tid
+            .store(unsafe { libc::gettid() }, Ordering::Relaxed);
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("worker", index).entered();
+        {
+            let _current = Current::enter(self, &local, index);
+            let counters = &activity.counters;
+            let mut since = self.stats.then(Instant::now);
+            while let Some(job) = self.wait(index, &local) {
+                since = since.map(|x| counters.idle(x));
+                activity.busy.store(true, Ordering::Relaxed);
+                self.execute(&sender, index, job, &mut state);
+                activity.busy.store(false, Ordering::Relaxed);
+                since = since.map(|x| counters.busy(x));
+            }
+            if let Some(since) = since {
+                counters.idle(since);
+            }
+
+            // run the jobs sent to us in the meantime and close the mailbox
+            loop {
+                while let Some(job) = self.take_mail(index) {
+                    self.execute(&sender, index, job, &mut state);
+                }
+                let mut mail = self.mail.lock().unwrap();
+                if mail[index - 1].as_ref().is_none_or(|x| x.is_empty()) {
+                    mail[index - 1] = None;
+                    break;
+                }
+            }
+        }
+        self.free.lock().unwrap().push((index - 1, local));
+        state
+    }
+
+    /// Execute a job on the worker by giving it to the handler with the sender.
+    fn execute(&self, sender: &Sender<'a, W, J>, index: usize, job: J, state: &mut W) {
+        let _done = Done(self);
+        let handler = self.handler;
+        if self.cancel.is_cancelled() {
+            drop(job);
+        } else if !self.catch_panics {
+            handler(sender, state, job);
+        } else if let Err(payload) = catch_unwind(AssertUnwindSafe(|| handler(sender, state, job)))
+        {
+            let panic = Panic {
+                worker: index,
+                payload,
+            };
+            self.panics.lock().unwrap().push(panic);
+        }
+    }
+
+    /// Return the number of queued jobs.
+    ///
+    /// This is only a snapshot if the workers are running.
+    pub(crate) fn queued(&self) -> usize {
+        let mail: usize = self
+            .mail
+            .lock()
+            .unwrap()
+            .iter()
+            .flatten()
+            .map(Vec::len)
+            .sum();
+        let local: usize = self.stealers.iter().map(Stealer::len).sum();
+        mail + self.prioritized.len() + self.injector.len() + local
+    }
+
+    /// Call the function on every queued job.
+    ///
+    /// The deques cannot be iterated, so their jobs are stolen and pushed to
+    /// the injector again.  This keeps the jobs pending but may change the
+    /// order of the local queues.
+    pub(crate) fn inspect(&self, mut f: impl FnMut(&J)) {
+        for job in self.mail.lock().unwrap().iter().flatten().flatten() {
+            f(job);
+        }
+        self.prioritized.for_each(&mut f);
+        let mut jobs = Vec::new();
+        while let Some(job) = std::iter::once(self.injector.steal())
+            .chain(self.stealers.iter().map(|x| x.steal()))
+            .find_map(|x| x.success())
+        {
+            jobs.push(job);
+        }
+        for job in jobs {
+            f(&job);
+            self.injector.push(job);
+            self.notify();
+        }
+    }
+
+    /// Take the panics caught so far.
+    pub(crate) fn take_panics(&self) -> Vec<Panic> {
+        std::mem::take(&mut self.panics.lock().unwrap())
+    }
+
+    /// Drop the jobs that are still queued and return their number.
+    pub(crate) fn drain(&self) -> usize {
+        let mut count = 0;
+        while let Some(job) = self.prioritized.pop().or_else(|| {
+            std::iter::once(self.injector.steal())
+                .chain(self.stealers.iter().map(|x| x.steal()))
+                .find_map(|x| x.success())
+        }) {
+            drop(job);
+            self.done();
+            count += 1;
+        }
+        count
+    }
+}
+
+/// Marks a worker as gone, even if a job panicked.
+struct Leave<'s, 'a, W, J>(&'s Shared<'a, W, J>, usize);
+
+impl<W, J> Drop for Leave<'_, '_, W, J> {
+    fn drop(&mut self) {
+        // drop the jobs sent to us if a job panicked
+        let jobs = self.0.mail.lock().unwrap()[self.1 - 1].take();
+        for job in jobs.into_iter().flatten() {
+            drop(job);
+            self.0.done();
+        }
+        let activity = &self.0.activity[self.1 - 1];
+        activity.tid.store(0, Ordering::Relaxed);
+        activity.busy.store(false, Ordering::Relaxed);
+        if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // nobody is left to finish the pending jobs
+            let _guard = self.0.lock.lock().unwrap();
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+/// Marks a job as finished, even if it panicked.
+struct Done<'s, 'a, W, J>(&'s Shared<'a, W, J>);
+
+impl<W, J> Drop for Done<'_, '_, W, J> {
+    fn drop(&mut self) {
+        self.0.done();
+    }
+}
+
+/// Registers a worker on the current thread and restores the previous one on drop.
+struct Current((*const (), *const (), usize));
+
+impl Current {
+    fn enter<W, J>(shared: &Shared<'_, W, J>, local: &Local<J>, index: usize) -> Self {
+        let current = (
+            shared as *const _ as *const (),
+            local as *const _ as *const (),
+            index,
+        );
+        Self(CURRENT.replace(current))
+    }
+}
+
+impl Drop for Current {
+    fn drop(&mut self) {
+        CURRENT.set(self.0);
+    }
+}
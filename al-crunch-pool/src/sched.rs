@@ -0,0 +1,91 @@
+//! CPU and I/O scheduling of the workers.
+
+use std::io::{Error, ErrorKind, Result};
+
+/// The scheduling policy of the workers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// Keep the policy of the creating thread.
+    #[default]
+    Inherit,
+    /// `SCHED_OTHER`, the default time-sharing policy.
+    Other,
+    /// `SCHED_BATCH` for CPU-intensive work that is not interactive.
+    Batch,
+    /// `SCHED_IDLE` to run only when nothing else wants the CPU.
+    ///
+    /// The nice value has no effect under this policy.
+    Idle,
+}
+
+/// The I/O priority of the workers as set by `ioprio_set`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoPriority {
+    /// The best-effort class with a level from 0 (highest) to 7 (lowest).
+    BestEffort(u8),
+    /// The idle class that gets the disk only when nobody else uses it.
+    Idle,
+}
+
+impl IoPriority {
+    /// Return the value for `ioprio_set`.
+    fn value(&self) -> Result<libc::c_int> {
+        const CLASS_SHIFT: libc::c_int = 13;
+        match *self {
+            Self::BestEffort(level) if level < 8 => Ok(2 << CLASS_SHIFT | level as libc::c_int),
+            Self::BestEffort(level) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid best-effort I/O level {level}"),
+            )),
+            Self::Idle => Ok(3 << CLASS_SHIFT),
+        }
+    }
+}
+
+/// How the workers are scheduled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Scheduling {
+    /// The nice value from -20 to 19.
+    pub nice: Option<i32>,
+    /// The scheduling policy.
+    pub policy: Policy,
+    /// The I/O priority.
+    pub io: Option<IoPriority>,
+}
+
+impl Scheduling {
+    /// Apply the settings to the current thread.
+    pub(crate) fn apply(&self) -> Result<()> {
+        let check = |x: libc::c_int, what: &str| match x {
+            0 => Ok(()),
+            _ => {
+                let e = Error::last_os_error();
+                Err(Error::new(e.kind(), format!("{what}: {e}")))
+            }
+        };
+        let policy = match self.policy {
+            Policy::Inherit => None,
+            Policy::Other => Some(libc::SCHED_OTHER),
+            Policy::Batch => Some(libc::SCHED_BATCH),
+            Policy::Idle => Some(libc::SCHED_IDLE),
+        };
+        if let Some(policy) = policy {
+            let param = libc::sched_param { sched_priority: 0 };
+            let x = unsafe { libc::sched_setscheduler(0, policy, &param) };
+            check(x, "setting the scheduling policy")?;
+        }
+        if let Some(nice) = self.nice {
+            // the nice value is per thread on Linux
+            let tid = unsafe { libc::gettid() } as libc::id_t;
+            let x = unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) };
+            check(x, "setting the nice value")?;
+        }
+        if let Some(io) = self.io {
+            const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+            let x =
+                unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, io.value()?) };
+            check(x as libc::c_int, "setting the I/O priority")?;
+        }
+        Ok(())
+    }
+}
@@ -1,49 +1,380 @@
 //! The sender object.
 
-use crate::channel;
+use crate::{
+    group::Group, handle, queue::Shared, sim, timer::TimerJob, CancelToken, JobGroup, JobHandle,
+    Stats,
+};
+use std::sync::{atomic::Ordering, Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
 
 /// The Sender type.
 ///
-/// Wraps a crossbeam::channel::Sender and some worker state to be able to implement `send` on it.
-pub struct Sender<W>(pub(crate) channel::Sender<SenderFunction<W>>);
+/// Wraps the queues of the pool to be able to implement `send` on it.  The
+/// generic type J is the type of the queued jobs.  See `Pool::with_handler`.
+pub struct Sender<'a, W, J = SenderFunction<'a, W>>(
+    pub(crate) Arc<Shared<'a, W, J>>,
+    /// Is it counted as a live sender?  The senders given to the handler are not.
+    bool,
+);
 
-impl<W> Sender<W> {
+/// The function running every job of a pool on the state of a worker.
+pub type Handler<'a, W, J> = fn(&Sender<'a, W, J>, &mut W, J);
+
+impl<'a, W, J> Sender<'a, W, J> {
+    /// Wrap the queues into the sender that is handed out with the pool.
+    pub(crate) fn new(shared: Arc<Shared<'a, W, J>>) -> Self {
+        Self(shared, true)
+    }
+
+    /// Wrap the queues into a sender for the handler that does not keep the workers alive.
+    pub(crate) fn uncounted(shared: Arc<Shared<'a, W, J>>) -> Self {
+        Self(shared, false)
+    }
+
+    /// Send a job to the handler of the pool.
+    ///
+    /// This is the `send` of a pool with a handler.  The job is queued or
+    /// handled synchronously just like with `send`.
+    pub fn send_value(&self, worker: &mut W, job: J) {
+        if self.is_cancelled() {
+            drop(job);
+        } else if self.0.is_full(None) {
+            self.0.count_inline();
+            (self.0.handler)(self, worker, job);
+        } else {
+            self.0.push(None, job);
+        }
+    }
+
+    /// Send a job to the handler after the delay.
+    ///
+    /// See `send_after`.
+    pub fn send_value_after(&self, delay: Duration, job: J) -> CancelToken
+    where
+        J: Send,
+    {
+        self.0
+            .add_timer(Instant::now() + delay, TimerJob::Once(job))
+    }
+
+    /// Send a clone of the job to the handler after every interval.
+    ///
+    /// See `send_every`.
+    pub fn send_value_every(&self, interval: Duration, job: J) -> CancelToken
+    where
+        J: Clone + Send + Sync + 'a,
+    {
+        let job = TimerJob::Every(Box::new(move || job.clone()), interval);
+        self.0.add_timer(Instant::now() + interval, job)
+    }
+
+    /// Return the full indiction of the underlying queue.
+    ///
+    /// This may be used to optimize the sending code-path.
+    pub fn is_full(&self) -> bool {
+        self.0.is_full(None)
+    }
+
+    /// Return the number of queued jobs.
+    pub fn queued(&self) -> usize {
+        self.0.queued()
+    }
+
+    /// Call the function on every queued job.
+    ///
+    /// This is meant for debugging the values queued for a handler.  The
+    /// jobs are taken out of the queues and put back, so this is only a
+    /// snapshot if the workers are running and the local order may change.
+    pub fn inspect(&self, f: impl FnMut(&J)) {
+        self.0.inspect(f)
+    }
+
+    /// Add the amount to a progress counter.
+    ///
+    /// The counter is the index of its name in `Progress::new`.  This is cheap
+    /// as every worker has its own counters.  Does nothing without `Options::progress`.
+    pub fn progress(&self, counter: usize, amount: u64) {
+        self.0.add_progress(counter, amount);
+    }
+
+    /// Return the statistics collected so far.
+    ///
+    /// See `Options::stats`.
+    pub fn stats(&self) -> Stats {
+        self.0.stats()
+    }
+
+    /// Return a handle to cancel the jobs.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.0.cancel.clone()
+    }
+
+    /// Return true if the jobs or the group of the running job were cancelled.
+    ///
+    /// Long running jobs should poll this to stop early.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancel.is_cancelled() || Group::current_is_cancelled()
+    }
+}
+
+impl<'a, W> Sender<'a, W> {
     /// Send the message.
     ///
-    /// The job is executed synchronously if the bounded channel is full.
+    /// The job is pushed to the local queue of the calling worker or to the
+    /// shared injector if we are not called from a worker.  It is executed
+    /// synchronously if that queue is full and dropped if the pool was cancelled.
     pub fn send<F>(&self, worker: &mut W, job: F)
     where
-        F: FnOnce(&mut W) + Send + 'static,
+        F: FnOnce(&mut W) + Send + 'a,
+    {
+        self.submit(worker, None, job);
+    }
+
+    /// Send the message with a label.
+    ///
+    /// The job runs in a `tracing` span with the label if the `tracing`
+    /// feature is enabled.  Otherwise this is the same as `send`.
+    pub fn send_labeled<F>(&self, worker: &mut W, label: &'static str, job: F)
+    where
+        F: FnOnce(&mut W) + Send + 'a,
+    {
+        self.submit(worker, None, labeled(label, job));
+    }
+
+    /// Send the message with a priority.
+    ///
+    /// The job goes ahead of all jobs with a lower priority and of all jobs
+    /// sent without one.  It is executed synchronously if the priority queue is full.
+    pub fn send_with_priority<F>(&self, worker: &mut W, priority: isize, job: F)
+    where
+        F: FnOnce(&mut W) + Send + 'a,
+    {
+        self.submit(worker, Some(priority), job);
+    }
+
+    /// Send the items in batches to the handler.
+    ///
+    /// Every batch of up to `size` items is a single job, so the boxing and
+    /// queueing is paid once per batch instead of once per item.  The batches
+    /// are executed synchronously if the queue is full just like with `send`.
+    /// The remaining items of a batch are dropped once the pool is cancelled.
+    pub fn send_batch<T, I, F>(&self, worker: &mut W, size: usize, items: I, handler: F)
+    where
+        W: 'a,
+        I: IntoIterator<Item = T>,
+        T: Send + 'a,
+        F: Fn(&Self, &mut W, T) + Clone + Send + 'a,
+    {
+        let mut items = items.into_iter().peekable();
+        while items.peek().is_some() {
+            let batch: Vec<T> = items.by_ref().take(size.max(1)).collect();
+            let sender = self.clone();
+            let handler = handler.clone();
+            self.send(worker, move |state| {
+                for item in batch {
+                    if sender.is_cancelled() {
+                        break;
+                    }
+                    handler(&sender, state, item);
+                }
+            });
+        }
+    }
+
+    /// Count the job against the group of the running job and submit it.
+    fn submit<F>(&self, worker: &mut W, priority: Option<isize>, job: F)
+    where
+        F: FnOnce(&mut W) + Send + 'a,
+    {
+        match Group::current() {
+            Some(group) => {
+                let member = group.join();
+                self.submit_job(worker, priority, move |state| member.run(|| job(state)));
+            }
+            None => self.submit_job(worker, priority, job),
+        }
+    }
+
+    /// Queue the job or execute it synchronously.
+    fn submit_job<F>(&self, worker: &mut W, priority: Option<isize>, job: F)
+    where
+        F: FnOnce(&mut W) + Send + 'a,
     {
         // execute the jobs - this also handles the zero-slots case
-        if self.0.is_full() {
+        if self.is_cancelled() {
+            drop(job);
+        } else if self.0.is_full(priority) {
+            self.0.count_inline();
             job(worker);
         } else {
-            match self.0.try_send(Box::new(job)) {
-                Ok(()) => {}
-                // there is a race condition on the previous check - thus execute it here instead
-                Err(channel::TrySendError::Full(job)) => job(worker),
-                Err(e) => {
-                    panic!("{}", e)
-                }
-            };
+            self.0.push(priority, Box::new(job));
         }
     }
 
-    /// Return the full indiction of the underlying queue.
+    /// Send the job after the delay.
     ///
-    /// This may be used to optimize the sending code-path.
-    pub fn is_full(&self) -> bool {
-        self.0.is_full()
+    /// The job is queued by the timer thread of the pool that is started with
+    /// the first timer.  It is never executed
+    /// synchronously.  Returns a token to cancel the timer.  Pending timers
+    /// are cancelled when the pool joins.  Without threads the job is dropped
+    /// and the token is already cancelled.
+    pub fn send_after<F>(&self, delay: Duration, job: F) -> CancelToken
+    where
+        F: FnOnce(&mut W) + Send + 'a,
+    {
+        let job: TimerJob<'a, SenderFunction<'a, W>> = TimerJob::Once(Box::new(job));
+        self.0.add_timer(Instant::now() + delay, job)
+    }
+
+    /// Send the job after every interval until the token is cancelled or the pool joins.
+    ///
+    /// Runs may overlap if a job takes longer than the interval.  See `send_after`.
+    pub fn send_every<F>(&self, interval: Duration, job: F) -> CancelToken
+    where
+        F: Fn(&mut W) + Send + Sync + 'a,
+    {
+        let job = Arc::new(job);
+        let job = TimerJob::Every(
+            Box::new(move || -> SenderFunction<'a, W> {
+                let job = job.clone();
+                Box::new(move |state: &mut W| job(state))
+            }),
+            interval,
+        );
+        self.0.add_timer(Instant::now() + interval, job)
+    }
+
+    /// Send a job and return a handle to its value.
+    ///
+    /// The job is queued or executed synchronously just like with `send`.
+    pub fn spawn<F, T>(&self, worker: &mut W, job: F) -> JobHandle<T>
+    where
+        F: FnOnce(&mut W) -> T + Send + 'a,
+        T: Send + 'a,
+    {
+        let (promise, handle) = handle::new();
+        self.send(worker, move |state| promise.set(job(state)));
+        handle
+    }
+
+    /// Wait for a spawned job and return its value.
+    ///
+    /// Queued jobs are executed on the worker while waiting, so that a job can
+    /// wait for the jobs it spawned without blocking its thread.  Returns None
+    /// if the job was dropped without running.
+    pub fn wait<T>(&self, worker: &mut W, mut handle: JobHandle<T>) -> Option<T> {
+        loop {
+            if let Poll::Ready(value) = handle.try_take() {
+                return value;
+            }
+            if !self.0.help(self, worker) && !sim::step() {
+                handle.wait_timeout(Duration::from_micros(100));
+            }
+        }
+    }
+
+    /// Run both jobs, potentially in parallel, and return their values.
+    ///
+    /// The first job runs on the calling worker while the second one is
+    /// offered to the pool.  If no other worker has taken it in the meantime,
+    /// it runs on the calling worker as well.  Both jobs run synchronously if
+    /// the pool has no threads.
+    ///
+    /// Panics if the second job panicked on another worker.
+    pub fn join<A, B, RA, RB>(&self, worker: &mut W, a: A, b: B) -> (RA, RB)
+    where
+        A: FnOnce(&mut W) -> RA,
+        B: FnOnce(&mut W) -> RB + Send + 'a,
+        RB: Send + 'a,
+    {
+        if self.0.workers() == 0 {
+            let ra = a(worker);
+            return (ra, b(worker));
+        }
+
+        // offer the second job to the pool
+        let slot = Arc::new(Mutex::new(Some(b)));
+        let handle = self.spawn(worker, {
+            let slot = slot.clone();
+            move |state| slot.lock().unwrap().take().map(|b| b(state))
+        });
+        let ra = a(worker);
+
+        // take it back if nobody has started it yet
+        let b = slot.lock().unwrap().take();
+        let rb = match b {
+            Some(b) => b(worker),
+            None => self
+                .wait(worker, handle)
+                .flatten()
+                .expect("the joined job panicked"),
+        };
+        (ra, rb)
+    }
+
+    /// Create a group to wait for or cancel some of the jobs.
+    pub fn group(&self) -> JobGroup<'a, W> {
+        JobGroup::new(self.clone())
     }
 }
 
-impl<W> Clone for Sender<W> {
-    /// Clone the underlying crossbeam::channel::Sender.
+impl<W, J> Clone for Sender<'_, W, J> {
+    /// Clone the sender and count it as live.
     fn clone(&self) -> Self {
-        Self(self.0.clone())
+        self.0.senders.fetch_add(1, Ordering::Relaxed);
+        Self::new(self.0.clone())
     }
 }
 
+impl<W, J> Drop for Sender<'_, W, J> {
+    /// Let the workers finish when the last live sender is gone.
+    fn drop(&mut self) {
+        if self.1 && self.0.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.shutdown();
+        }
+    }
+}
+
+impl<W, J: std::fmt::Debug> std::fmt::Debug for Sender<'_, W, J> {
+    /// List the queued jobs.  See `inspect`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sender")
+            .field("queued", &Queued(self))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Formats the queued jobs as a list.
+struct Queued<'s, 'a, W, J>(&'s Sender<'a, W, J>);
+
+impl<W, J: std::fmt::Debug> std::fmt::Debug for Queued<'_, '_, W, J> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut list = f.debug_list();
+        self.0.inspect(|x| {
+            list.entry(x);
+        });
+        list.finish()
+    }
+}
+
+/// Run the job in a span with the label.
+#[cfg(feature = "tracing")]
+fn labeled<'a, W, F>(label: &'static str, job: F) -> impl FnOnce(&mut W) + Send + 'a
+where
+    F: FnOnce(&mut W) + Send + 'a,
+{
+    move |state| {
+        let _span = tracing::info_span!("job", label).entered();
+        job(state)
+    }
+}
+
+/// Without tracing the label is ignored.
+#[cfg(not(feature = "tracing"))]
+fn labeled<F>(_label: &'static str, job: F) -> F {
+    job
+}
+
 /// The sender function.
-pub(crate) type SenderFunction<W> = Box<dyn FnOnce(&mut W) + Send>;
+pub(crate) type SenderFunction<'a, W> = Box<dyn FnOnce(&mut W) + Send + 'a>;
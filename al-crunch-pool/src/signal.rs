@@ -0,0 +1,282 @@
+//! Cancelling the jobs on signals.
+//!
+//! The signal handler only writes the signal number into a pipe as it may
+//! not take any locks.  A thread reads the pipe and cancels the registered
+//! tokens registered for it.  The previous handlers are restored once
+//! nobody is registered for a signal anymore.  A token that fired is no
+//! longer registered for any of its signals.
+
+use crate::CancelToken;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+/// The write end of the pipe.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// The registered tokens and signals.
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    next: 0,
+    tokens: Vec::new(),
+    signals: Vec::new(),
+});
+
+/// A signal somebody is registered for.
+struct Registered {
+    signal: libc::c_int,
+    /// The number of guards for the signal.
+    guards: usize,
+    /// The action before our handler was installed.
+    previous: libc::sigaction,
+}
+
+/// A token with the signals it is registered for.
+struct Token {
+    /// Identifies the guard of the token.
+    id: u64,
+    token: Weak<AtomicBool>,
+    signals: Vec<libc::c_int>,
+}
+
+/// The tokens to cancel and the signals to catch.
+struct Registry {
+    /// The id of the next guard.
+    next: u64,
+    /// The tokens are removed once cancelled or when their guard is dropped.
+    tokens: Vec<Token>,
+    signals: Vec<Registered>,
+}
+
+impl Registry {
+    /// Install our handler for the signal unless it is already there.
+    fn add(&mut self, signal: libc::c_int) -> Result<()> {
+        match self.signals.iter_mut().find(|x| x.signal == signal) {
+            Some(registered) => registered.guards += 1,
+            None => {
+                let previous = install(signal)?;
+                self.signals.push(Registered {
+                    signal,
+                    guards: 1,
+                    previous,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Release a guard for the signal and restore its action if it was the last one.
+    fn remove(&mut self, signal: libc::c_int) {
+        let Some(index) = self.signals.iter().position(|x| x.signal == signal) else {
+            return;
+        };
+        self.signals[index].guards -= 1;
+        if self.signals[index].guards == 0 {
+            let previous = self.signals.swap_remove(index).previous;
+            unsafe { libc::sigaction(signal, &previous, core::ptr::null_mut()) };
+        }
+    }
+
+    /// Unregister the token and release all its signals.
+    fn release(&mut self, token: Token) {
+        for signal in token.signals {
+            self.remove(signal);
+        }
+    }
+}
+
+/// Install the handler for the signal and return the previous action.
+fn install(signal: libc::c_int) -> Result<libc::sigaction> {
+    let mut action: libc::sigaction = unsafe { core::mem::zeroed() };
+    action.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+    let mut previous: libc::sigaction = unsafe { core::mem::zeroed() };
+    if unsafe { libc::sigaction(signal, &action, &mut previous) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(previous)
+}
+
+/// Write the signal into the pipe.
+extern "C" fn handler(signal: libc::c_int) {
+    // SAFETY: write is async-signal-safe and errno is restored
+    unsafe {
+        let errno = *libc::__errno_location();
+        let byte = signal as u8;
+        libc::write(
+            PIPE.load(Ordering::Relaxed),
+            &byte as *const u8 as *const _,
+            1,
+        );
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Read the pipe and cancel the tokens registered for every signal.
+///
+/// The tokens are unregistered from all their signals, so that a second
+/// signal reaches the previous action unless another token waits for it.
+fn watch(fd: libc::c_int) {
+    loop {
+        let mut byte = 0u8;
+        let res = unsafe { libc::read(fd, &mut byte as *mut u8 as *mut _, 1) };
+        if res < 0 && Error::last_os_error().kind() == ErrorKind::Interrupted {
+            continue;
+        }
+        if res != 1 {
+            return;
+        }
+        let signal = byte as libc::c_int;
+        let mut registry = REGISTRY.lock().unwrap();
+        let (fired, rest) = std::mem::take(&mut registry.tokens)
+            .into_iter()
+            .partition(|x: &Token| x.signals.contains(&signal));
+        registry.tokens = rest;
+        for token in fired {
+            if let Some(cancel) = token.token.upgrade() {
+                CancelToken(cancel).cancel();
+            }
+            registry.release(token);
+        }
+    }
+}
+
+/// Create the pipe and start the thread reading it.
+fn start() -> Result<()> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    PIPE.store(fds[1], Ordering::Relaxed);
+    std::thread::Builder::new()
+        .name("signals".into())
+        .spawn(move || watch(fds[0]))?;
+    Ok(())
+}
+
+/// Keeps a token registered for signals.
+///
+/// Dropping it unregisters the token.  The previous signal handlers are
+/// restored when the last guard for a signal is dropped or has fired.
+#[derive(Debug)]
+pub struct SignalGuard(u64);
+
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        let mut registry = REGISTRY.lock().unwrap();
+        if let Some(index) = registry.tokens.iter().position(|x| x.id == self.0) {
+            let token = registry.tokens.swap_remove(index);
+            registry.release(token);
+        }
+    }
+}
+
+/// Cancel the token when the process receives one of the signals.
+pub(crate) fn register(token: &CancelToken, signals: &[libc::c_int]) -> Result<SignalGuard> {
+    static STARTED: OnceLock<std::result::Result<(), ErrorKind>> = OnceLock::new();
+    STARTED
+        .get_or_init(|| start().map_err(|e| e.kind()))
+        .map_err(|kind| Error::new(kind, "starting the signal thread"))?;
+    let mut registry = REGISTRY.lock().unwrap();
+    let id = registry.next;
+    registry.next += 1;
+    let mut added = Vec::new();
+    let res = signals.iter().try_for_each(|&signal| {
+        registry.add(signal)?;
+        added.push(signal);
+        Ok(())
+    });
+    registry.tokens.push(Token {
+        id,
+        token: Arc::downgrade(&token.0),
+        signals: added,
+    });
+
+    // a failed guard unregisters on drop without holding the lock
+    drop(registry);
+    let guard = SignalGuard(id);
+    res.map(|()| guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::CancelToken;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, Instant};
+
+    static FIRED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn previous(_: libc::c_int) {
+        FIRED.store(true, Ordering::SeqCst);
+    }
+
+    /// Return the current handler of the signal.
+    fn current(signal: libc::c_int) -> libc::sighandler_t {
+        let mut action: libc::sigaction = unsafe { core::mem::zeroed() };
+        unsafe { libc::sigaction(signal, core::ptr::null(), &mut action) };
+        action.sa_sigaction
+    }
+
+    /// Wait until the condition holds.
+    fn wait(f: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !f() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timeout");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn restore() {
+        let signal = libc::SIGUSR1;
+        let mut action: libc::sigaction = unsafe { core::mem::zeroed() };
+        action.sa_sigaction = previous as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        unsafe { libc::sigaction(signal, &action, core::ptr::null_mut()) };
+        let previous = action.sa_sigaction;
+
+        // the previous handler is back once the signal fired
+        let token = CancelToken::default();
+        let guard = token.cancel_on_signals(&[signal]).unwrap();
+        assert_ne!(current(signal), previous);
+        unsafe { libc::raise(signal) };
+        wait(|| token.is_cancelled());
+        wait(|| current(signal) == previous);
+        assert!(!FIRED.load(Ordering::SeqCst));
+
+        // or when the last guard is dropped
+        let other = CancelToken::default();
+        let guard2 = other.cancel_on_signals(&[signal]).unwrap();
+        assert_ne!(current(signal), previous);
+        drop(guard);
+        assert_ne!(current(signal), previous);
+        drop(guard2);
+        assert_eq!(current(signal), previous);
+        unsafe { libc::raise(signal) };
+        assert!(FIRED.load(Ordering::SeqCst));
+        assert!(!other.is_cancelled());
+    }
+
+    #[test]
+    fn separate() {
+        // both signals are ignored by default
+        let (first, second) = (libc::SIGWINCH, libc::SIGURG);
+        let default = current(libc::SIGUSR2);
+
+        // only the token registered for the signal is cancelled
+        let token = CancelToken::default();
+        let _guard = token.cancel_on_signals(&[first, libc::SIGUSR2]).unwrap();
+        let other = CancelToken::default();
+        let _guard2 = other.cancel_on_signals(&[second]).unwrap();
+        unsafe { libc::raise(first) };
+        wait(|| token.is_cancelled());
+        assert!(!other.is_cancelled());
+
+        // all signals of the fired token are restored
+        wait(|| current(libc::SIGUSR2) == default);
+        assert_eq!(current(first), libc::SIG_DFL);
+        assert_ne!(current(second), libc::SIG_DFL);
+        unsafe { libc::raise(second) };
+        wait(|| other.is_cancelled());
+        wait(|| current(second) == libc::SIG_DFL);
+    }
+}
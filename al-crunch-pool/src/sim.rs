@@ -0,0 +1,229 @@
+//! Deterministic simulation of the workers on the calling thread.
+//!
+//! The virtual workers have their own states and local queues just like the
+//! threads of a pool.  A generator seeded by the user picks the worker that
+//! runs the next job and the worker it steals from.  As the jobs run to
+//! completion one after the other, the same seed gives the same order.
+//!
+//! A blocking wait on the simulating thread runs the next jobs on the
+//! virtual workers that are not blocked themselves.
+
+use crate::{JoinError, Options, Sender, Shared, Stats, WorkerInfo};
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+
+thread_local! {
+    /// Runs the next job of the simulation on this thread.
+    static STEP: Cell<Option<*const dyn Fn() -> bool>> = const { Cell::new(None) };
+}
+
+/// The splitmix64 generator.
+struct Rng(u64);
+
+impl Rng {
+    /// Return a number below the bound.
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        ((z ^ (z >> 31)) % bound as u64) as usize
+    }
+}
+
+/// Run the next job if the calling thread runs a simulation.
+///
+/// Returns false otherwise.  Panics if there is no job the virtual workers
+/// could run, as the caller would wait forever.
+pub(crate) fn step() -> bool {
+    let Some(step) = STEP.get() else {
+        return false;
+    };
+    // SAFETY: the pointer is valid while the `Simulating` guard lives
+    if !unsafe { (*step)() } {
+        panic!("waiting for a job that cannot run in the simulation");
+    }
+    true
+}
+
+/// Registers a simulation on the current thread and restores the previous one on drop.
+struct Simulating<'s>(Option<*const dyn Fn() -> bool>, PhantomData<&'s ()>);
+
+impl<'s> Simulating<'s> {
+    fn enter(step: &'s dyn Fn() -> bool) -> Self {
+        // SAFETY: the lifetime is only erased while the guard borrows the closure
+        let step: &'static dyn Fn() -> bool = unsafe { std::mem::transmute(step) };
+        Self(STEP.replace(Some(step)), PhantomData)
+    }
+}
+
+impl Drop for Simulating<'_> {
+    fn drop(&mut self) {
+        STEP.set(self.0);
+    }
+}
+
+/// Run the scoped pool as a simulation and collect the statistics.
+pub(crate) fn simulate<'env, W, Y: Send, X>(
+    seed: u64,
+    workers: usize,
+    options: Options,
+    create: impl Fn(WorkerInfo) -> W,
+    destroy: impl Fn(W) -> Y,
+    init: impl FnOnce(&Sender<'env, W>) -> X,
+    combine: impl Fn(X, Y) -> X,
+) -> (Result<X, JoinError<X>>, Stats) {
+    let shared = Shared::new(workers, &options);
+
+    // there is no timer thread
+    let _ = shared.timers.close();
+
+    // the virtual workers with their local queues
+    let workers: Vec<_> = std::iter::from_fn(|| shared.take_local())
+        .map(|(slot, local)| {
+            let info = WorkerInfo {
+                index: slot + 1,
+                ..WorkerInfo::caller()
+            };
+            RefCell::new((info.index, local, create(info)))
+        })
+        .collect();
+
+    // run the next job on a random worker that is not blocked in a job
+    let rng = RefCell::new(Rng(seed));
+    let step = || {
+        let idle: Vec<_> = workers
+            .iter()
+            .filter(|x| x.try_borrow_mut().is_ok())
+            .collect();
+        if idle.is_empty() {
+            return false;
+        }
+        let (next, victim) = {
+            let mut rng = rng.borrow_mut();
+            (rng.below(idle.len()), rng.below(workers.len()))
+        };
+        let (index, local, worker) = &mut *idle[next].borrow_mut();
+        shared.step(*index, local, worker, victim)
+    };
+
+    // submit the initial jobs and run them until the queues are empty
+    let mut state = {
+        let _simulating = Simulating::enter(&step);
+        let state = init(&Sender::new(shared.clone()));
+        while !shared.is_empty() && step() {}
+        state
+    };
+
+    // combine all results in the order of the workers
+    for worker in workers {
+        state = combine(state, destroy(worker.into_inner().2));
+    }
+    (JoinError::check(&shared, Vec::new(), state), shared.stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{execute, execute_with_stats, Options, Sender};
+
+    /// The index of a worker and the nodes it ran.
+    type Trace = (usize, Vec<u32>);
+
+    /// Expand a binary tree of jobs and record the nodes.
+    fn expand<'a>(sender: &Sender<'a, Trace>, state: &mut Trace, node: u32) {
+        state.1.push(node);
+        if node < 32 {
+            for child in [2 * node, 2 * node + 1] {
+                let s = sender.clone();
+                sender.send(state, move |state| expand(&s, state, child));
+            }
+        }
+    }
+
+    /// Return the traces of the caller and the workers and the inline jobs.
+    fn run(seed: u64) -> (Vec<Trace>, u64) {
+        let options = Options::default().slots(1).stats().simulate(seed, 3);
+        let (traces, stats) = execute_with_stats(
+            options,
+            |info| (info.index, Vec::new()),
+            |x| x,
+            |s| {
+                let mut caller = (0, Vec::new());
+                expand(s, &mut caller, 1);
+                vec![caller]
+            },
+            |mut x, y| {
+                x.push(y);
+                x
+            },
+        );
+        (traces, stats.total().inline)
+    }
+
+    #[test]
+    fn deterministic() {
+        let (traces, inline) = run(1);
+        assert_eq!(traces.len(), 4);
+        assert!(inline > 0);
+        let mut nodes: Vec<_> = traces.iter().flat_map(|x| x.1.clone()).collect();
+        nodes.sort();
+        assert_eq!(nodes, (1..64).collect::<Vec<_>>());
+
+        assert_eq!(run(1), (traces.clone(), inline));
+        assert_ne!(run(2).0, traces);
+    }
+
+    #[test]
+    fn blocking() {
+        // the caller waits before any job ran
+        let options = Options::default().simulate(1, 2);
+        let sum = execute(
+            options,
+            |_| 0u32,
+            |x| x,
+            |s| s.spawn(&mut 0, |_| 42).wait().unwrap(),
+            |a, b| a + b,
+        );
+        assert_eq!(sum, 42);
+
+        // a job waits for a group on the other worker
+        let options = Options::default().simulate(1, 2);
+        let sum = execute(
+            options,
+            |_| 0u32,
+            |x| x,
+            |s| {
+                let sender = s.clone();
+                s.send(&mut 0, move |state| {
+                    let group = sender.group();
+                    for _ in 0..4 {
+                        group.send(state, |state| *state += 1);
+                    }
+                    group.wait();
+                    *state += 10;
+                });
+                0
+            },
+            |a, b| a + b,
+        );
+        assert_eq!(sum, 14);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot run in the simulation")]
+    fn blocking_deadlock() {
+        let options = Options::default().simulate(1, 1);
+        execute(
+            options,
+            |_| (),
+            |x| x,
+            |s| {
+                let sender = s.clone();
+                s.send(&mut (), move |state| {
+                    sender.spawn(state, |_| ()).wait();
+                });
+            },
+            |_, _| (),
+        );
+    }
+}
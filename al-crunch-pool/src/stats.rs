@@ -0,0 +1,121 @@
+//! Runtime statistics of the workers.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The counters of a single worker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// The jobs taken from the queues and executed.
+    pub jobs: u64,
+    /// The jobs executed synchronously as the queue was full.
+    pub inline: u64,
+    /// The time spent running jobs.
+    pub busy: Duration,
+    /// The time spent waiting for jobs.
+    pub idle: Duration,
+    /// The maximum number of jobs in the local queue.
+    pub peak_queue: usize,
+}
+
+impl WorkerStats {
+    /// Return the share of the time the worker was busy.
+    pub fn utilization(&self) -> f64 {
+        let total = self.busy + self.idle;
+        if total.is_zero() {
+            return 0.0;
+        }
+        self.busy.as_secs_f64() / total.as_secs_f64()
+    }
+}
+
+/// The statistics of a pool.
+///
+/// They are only collected with `Options::stats`, otherwise all counters are zero.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The counters per worker.  Index i belongs to worker i + 1.
+    pub workers: Vec<WorkerStats>,
+    /// The jobs executed synchronously by threads outside of the pool.
+    pub inline: u64,
+}
+
+impl Stats {
+    /// Sum up the counters of all workers and the inline jobs from the outside.
+    ///
+    /// The peak queue depth is the maximum of the workers.
+    pub fn total(&self) -> WorkerStats {
+        self.workers.iter().fold(
+            WorkerStats {
+                inline: self.inline,
+                ..Default::default()
+            },
+            |a, b| WorkerStats {
+                jobs: a.jobs + b.jobs,
+                inline: a.inline + b.inline,
+                busy: a.busy + b.busy,
+                idle: a.idle + b.idle,
+                peak_queue: a.peak_queue.max(b.peak_queue),
+            },
+        )
+    }
+}
+
+/// The counters updated by a worker.
+#[derive(Default)]
+pub(crate) struct Counters {
+    jobs: AtomicU64,
+    inline: AtomicU64,
+    /// Nanoseconds.
+    busy: AtomicU64,
+    /// Nanoseconds.
+    idle: AtomicU64,
+    peak_queue: AtomicUsize,
+}
+
+/// Add the time since the instant to the counter and return the current time.
+fn elapsed(counter: &AtomicU64, since: Instant) -> Instant {
+    let now = Instant::now();
+    let nanos = now.duration_since(since).as_nanos() as u64;
+    counter.fetch_add(nanos, Ordering::Relaxed);
+    now
+}
+
+impl Counters {
+    /// Count a job that ran since the instant.
+    pub(crate) fn busy(&self, since: Instant) -> Instant {
+        self.job();
+        elapsed(&self.busy, since)
+    }
+
+    /// Count the time waited since the instant.
+    pub(crate) fn idle(&self, since: Instant) -> Instant {
+        elapsed(&self.idle, since)
+    }
+
+    /// Count a job without measuring its time.
+    pub(crate) fn job(&self) {
+        self.jobs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a job executed synchronously.
+    pub(crate) fn inline(&self) {
+        self.inline.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the depth of the local queue.
+    pub(crate) fn queue(&self, depth: usize) {
+        self.peak_queue.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    /// Return the current values.
+    pub(crate) fn get(&self) -> WorkerStats {
+        WorkerStats {
+            jobs: self.jobs.load(Ordering::Relaxed),
+            inline: self.inline.load(Ordering::Relaxed),
+            busy: Duration::from_nanos(self.busy.load(Ordering::Relaxed)),
+            idle: Duration::from_nanos(self.idle.load(Ordering::Relaxed)),
+            peak_queue: self.peak_queue.load(Ordering::Relaxed),
+        }
+    }
+}
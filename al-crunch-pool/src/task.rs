@@ -0,0 +1,143 @@
+//! Running futures on the workers.
+//!
+//! A future is polled by a job on whatever worker takes it.  Its waker sends
+//! a new job to poll it again.
+
+use crate::{handle, JobHandle, Sender};
+use std::cell::Cell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Wake, Waker};
+
+thread_local! {
+    /// The shared state of the pool and the worker state while a future is polled.
+    static POLLING: Cell<(*const (), *mut ())> = const { Cell::new((std::ptr::null(), std::ptr::null_mut())) };
+}
+
+/// Gives a future access to the state of the worker polling it.
+pub struct StateRef<W> {
+    shared: *const (),
+    _state: PhantomData<fn(&mut W)>,
+}
+
+// SAFETY: the pointer is only compared and never dereferenced
+unsafe impl<W> Send for StateRef<W> {}
+unsafe impl<W> Sync for StateRef<W> {}
+
+impl<W> Clone for StateRef<W> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<W> Copy for StateRef<W> {}
+
+impl<W> StateRef<W> {
+    /// Run the closure on the state of the worker that polls the future.
+    ///
+    /// The future may continue on another worker after the next `await`.
+    /// Panics if it is called outside of the future or recursively.
+    pub fn with<R>(&self, f: impl FnOnce(&mut W) -> R) -> R {
+        let (shared, state) = POLLING.get();
+        assert!(
+            shared == self.shared && !state.is_null(),
+            "the worker state is only available while the future is polled"
+        );
+        let _restore = Restore(POLLING.replace((shared, std::ptr::null_mut())));
+        // SAFETY: the pointer was set by `Task::poll` for the state of this pool
+        // and is taken out while the reference lives
+        f(unsafe { &mut *(state as *mut W) })
+    }
+}
+
+/// Restores the polling state on drop.
+struct Restore((*const (), *mut ()));
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        POLLING.set(self.0);
+    }
+}
+
+/// A future that is polled by the jobs of a pool.
+struct Task<W> {
+    future: Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+    /// Is a job to poll the future queued?
+    scheduled: AtomicBool,
+    sender: Sender<'static, W>,
+}
+
+impl<W: 'static> Task<W> {
+    /// Queue a job to poll the future unless there is one already.
+    ///
+    /// The job is never executed synchronously as there is no state to run it on.
+    /// Without workers the future is dropped instead as nobody would poll it.
+    fn schedule(self: Arc<Self>) {
+        if self.sender.0.workers() == 0 {
+            self.future.lock().unwrap().take();
+        } else if !self.scheduled.swap(true, Ordering::SeqCst) {
+            let shared = self.sender.0.clone();
+            shared.push(None, Box::new(move |state| self.poll(state)));
+        }
+    }
+
+    /// Poll the future on the state of the worker.
+    fn poll(self: Arc<Self>, state: &mut W) {
+        self.scheduled.store(false, Ordering::SeqCst);
+        let mut future = self.future.lock().unwrap();
+        let Some(pinned) = future.as_mut() else {
+            return;
+        };
+        let waker = Waker::from(self.clone());
+        let polling = (
+            Arc::as_ptr(&self.sender.0) as *const (),
+            state as *mut W as *mut (),
+        );
+        let _restore = Restore(POLLING.replace(polling));
+        if pinned
+            .as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_ready()
+        {
+            *future = None;
+        }
+    }
+}
+
+impl<W: 'static> Wake for Task<W> {
+    fn wake(self: Arc<Self>) {
+        self.schedule();
+    }
+}
+
+impl<W: 'static> Sender<'static, W> {
+    /// Run the future returned by the closure on the workers.
+    ///
+    /// The future gets access to the state of the worker that polls it.  It
+    /// is polled by a job that is queued again whenever it is woken up.
+    /// Returns a handle to await or wait for the output.  The handle returns
+    /// None if the pool has no workers.
+    pub fn spawn_async<F, Fut>(&self, f: F) -> JobHandle<Fut::Output>
+    where
+        F: FnOnce(StateRef<W>) -> Fut,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let (promise, handle) = handle::new();
+        let state = StateRef {
+            shared: Arc::as_ptr(&self.0) as *const (),
+            _state: PhantomData,
+        };
+        let future = f(state);
+        let task = Task {
+            future: Mutex::new(Some(Box::pin(async move { promise.set(future.await) }))),
+            scheduled: AtomicBool::new(false),
+            sender: self.clone(),
+        };
+        Arc::new(task).schedule();
+        handle
+    }
+}
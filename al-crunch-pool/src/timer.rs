@@ -0,0 +1,172 @@
+//! Delayed and periodic jobs.
+//!
+//! The timers are kept in a heap ordered by their due time.  A dedicated
+//! thread per pool sleeps until the next one is due and queues its job.  It
+//! is started with the first timer.
+
+use crate::CancelToken;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::{Condvar, Mutex};
+use std::thread::{Builder, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The job of a timer.  J is the type of the queued jobs.
+pub(crate) enum TimerJob<'a, J> {
+    /// Queued once.
+    Once(J),
+    /// Queued again after every interval.  The function creates the job to queue.
+    Every(Box<dyn Fn() -> J + Send + 'a>, Duration),
+}
+
+/// A pending timer.
+struct Entry<'a, J> {
+    due: Reverse<(Instant, u64)>,
+    token: CancelToken,
+    job: TimerJob<'a, J>,
+}
+
+impl<J> PartialEq for Entry<'_, J> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<J> Eq for Entry<'_, J> {}
+
+impl<J> PartialOrd for Entry<'_, J> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<J> Ord for Entry<'_, J> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.due.cmp(&other.due)
+    }
+}
+
+/// The pending timers.
+struct State<'a, J> {
+    /// The earliest timer first.
+    heap: BinaryHeap<Entry<'a, J>>,
+    /// Keeps timers with the same due time in order.
+    sequence: u64,
+    /// No timers are accepted anymore.
+    closed: bool,
+    /// Builds the timer thread until it is started.
+    builder: Option<Builder>,
+    /// The timer thread once it was started.
+    thread: Option<JoinHandle<()>>,
+}
+
+/// The timers of a pool.
+pub(crate) struct Timers<'a, J> {
+    state: Mutex<State<'a, J>>,
+    /// Wakes up the timer thread.
+    wakeup: Condvar,
+}
+
+impl<'a, J> Timers<'a, J> {
+    /// Create the timers that start their thread with the builder.
+    pub(crate) fn new(builder: Builder) -> Self {
+        Self {
+            state: Mutex::new(State {
+                heap: BinaryHeap::new(),
+                sequence: 0,
+                closed: false,
+                builder: Some(builder),
+                thread: None,
+            }),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    /// Add a timer and return a token to cancel it.
+    ///
+    /// The timer thread is started with the first timer by calling `start`
+    /// on the builder.  The job is dropped if the timers are already closed.
+    pub(crate) fn add(
+        &self,
+        due: Instant,
+        job: TimerJob<'a, J>,
+        start: impl FnOnce(Builder) -> std::io::Result<JoinHandle<()>>,
+    ) -> CancelToken {
+        let token = CancelToken::default();
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            token.cancel();
+            return token;
+        }
+        if let Some(builder) = state.builder.take() {
+            state.thread = Some(start(builder).expect("spawning the timer thread"));
+        }
+        state.sequence += 1;
+        let entry = Entry {
+            due: Reverse((due, state.sequence)),
+            token: token.clone(),
+            job,
+        };
+        state.heap.push(entry);
+        self.wakeup.notify_one();
+        token
+    }
+
+    /// Cancel all pending timers and join the timer thread.
+    ///
+    /// Returns the panic of the timer thread.
+    pub(crate) fn close(&self) -> std::thread::Result<()> {
+        let (heap, thread) = {
+            let mut state = self.state.lock().unwrap();
+            state.closed = true;
+            self.wakeup.notify_one();
+            (std::mem::take(&mut state.heap), state.thread.take())
+        };
+        drop(heap);
+        thread.map_or(Ok(()), |x| x.join())
+    }
+
+    /// Queue the jobs when they are due until the timers are closed.
+    pub(crate) fn run(&self, push: impl Fn(J)) {
+        let mut state = self.state.lock().unwrap();
+        while !state.closed {
+            let Some(next) = state.heap.peek() else {
+                state = self.wakeup.wait(state).unwrap();
+                continue;
+            };
+            let now = Instant::now();
+            let due = next.due.0 .0;
+            if due > now {
+                state = self.wakeup.wait_timeout(state, due - now).unwrap().0;
+                continue;
+            }
+            let entry = state.heap.pop().unwrap();
+            if entry.token.is_cancelled() {
+                continue;
+            }
+
+            // queue the job without holding the lock
+            drop(state);
+            let again = match entry.job {
+                TimerJob::Once(job) => {
+                    push(job);
+                    None
+                }
+                TimerJob::Every(job, interval) => {
+                    push(job());
+                    Some((due + interval, TimerJob::Every(job, interval)))
+                }
+            };
+            state = self.state.lock().unwrap();
+            if let Some((due, job)) = again.filter(|_| !state.closed) {
+                state.sequence += 1;
+                let entry = Entry {
+                    due: Reverse((due.max(now), state.sequence)),
+                    token: entry.token,
+                    job,
+                };
+                state.heap.push(entry);
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Setting up the worker threads.
+
+use crate::{
+    queue::{Local, Shared},
+    sender::SenderFunction,
+    CpuSet, Scheduling,
+};
+use std::sync::{Arc, Mutex};
+
+/// Describes a worker to the closure creating its state.
+#[derive(Clone, Copy, Debug)]
+pub struct WorkerInfo {
+    /// The index of the worker.  Numbered from one.
+    ///
+    /// Zero is the calling thread in `execute_dag`, the parallel iterators and
+    /// the stages of a `Pipeline`.
+    pub index: usize,
+    /// The CPUs the worker is pinned to.
+    pub cpus: Option<CpuSet>,
+    /// The CPU and I/O scheduling of the worker.
+    pub scheduling: Scheduling,
+}
+
+impl WorkerInfo {
+    /// Describe the calling thread that runs jobs synchronously.
+    pub(crate) fn caller() -> Self {
+        Self {
+            index: 0,
+            cpus: None,
+            scheduling: Scheduling::default(),
+        }
+    }
+
+    /// Apply the settings to the current thread.
+    ///
+    /// Failures panic the worker to report them when the pool joins.
+    pub(crate) fn setup(&self) {
+        if let Some(cpus) = &self.cpus {
+            if let Err(e) = cpus.apply() {
+                panic!("pinning worker {} to CPUs {cpus:?}: {e}", self.index);
+            }
+        }
+        if let Err(e) = self.scheduling.apply() {
+            panic!("scheduling worker {}: {e}", self.index);
+        }
+    }
+}
+
+/// Starts a worker thread on a local queue and returns its handle.
+pub(crate) type Spawn<'s, J, H> = Box<dyn FnMut(WorkerInfo, Local<J>) -> H + Send + 's>;
+
+/// The worker threads of a pool that may grow and shrink.
+///
+/// The generic type H is the handle of a thread and J the type of the jobs.
+pub(crate) struct Workers<'s, 'a, W, H, J = SenderFunction<'a, W>> {
+    pub(crate) shared: Arc<Shared<'a, W, J>>,
+    /// Describes the workers per local queue.
+    infos: Vec<WorkerInfo>,
+    /// Starts the threads.
+    spawn: Mutex<Spawn<'s, J, H>>,
+    /// The handles of the threads with the index of their worker.
+    handles: Mutex<Vec<(usize, H)>>,
+}
+
+impl<'s, 'a, W, H, J> Workers<'s, 'a, W, H, J> {
+    /// Start the given number of workers.
+    pub(crate) fn new(
+        shared: Arc<Shared<'a, W, J>>,
+        infos: Vec<WorkerInfo>,
+        threads: usize,
+        spawn: Spawn<'s, J, H>,
+    ) -> Self {
+        let res = Self {
+            shared,
+            infos,
+            spawn: Mutex::new(spawn),
+            handles: Mutex::new(Vec::new()),
+        };
+        for _ in 0..threads {
+            res.add();
+        }
+        res
+    }
+
+    /// Start another worker.  Returns false if the maximum number is reached.
+    pub(crate) fn add(&self) -> bool {
+        let Some((slot, local)) = self.shared.take_local() else {
+            return false;
+        };
+        let info = self.infos[slot];
+        let handle = (self.spawn.lock().unwrap())(info, local);
+        self.handles.lock().unwrap().push((info.index, handle));
+        true
+    }
+
+    /// Take the handles of all threads started so far.
+    pub(crate) fn take_handles(&self) -> Vec<(usize, H)> {
+        std::mem::take(&mut self.handles.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{try_execute, IoPriority, Options, Pool};
+
+    /// Options with a setup that fails on every worker.
+    fn failing() -> Options {
+        Options::default()
+            .threads(Some(2))
+            .io_priority(IoPriority::BestEffort(8))
+    }
+
+    #[test]
+    fn failed_setup() {
+        let res = try_execute(
+            failing(),
+            |_| (),
+            |_| (),
+            |s| s.send(&mut (), |_| ()),
+            |_, _| (),
+        );
+        let e = res.unwrap_err();
+        assert_eq!(e.panics.len(), 2);
+        assert!(e.panics[0]
+            .message()
+            .unwrap()
+            .starts_with("scheduling worker"));
+        assert_eq!(e.queued, 1);
+
+        let pool = Pool::new(failing(), (), |_| (), |_| ());
+        pool.wait_idle();
+        assert_eq!(pool.try_join().unwrap_err().panics.len(), 2);
+    }
+}